@@ -0,0 +1,765 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+
+use http::{header::HeaderName, Method};
+use regex::Regex;
+use rustls::{ClientConfig, ServerConfig};
+
+use crate::rewrite::HeaderRule;
+use crate::routing::{AttributeMatch, HostPattern, Route, RouteAction};
+use crate::tls::{client_config, load_roots, server_config};
+
+pub const CONFIG_PATH: &str = "src/serverConfig.txt";
+pub const DEFAULT_POOL: &str = "default";
+
+// What the backend sees as the request's Host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostHeader {
+    // The backend's own ip:port.
+    Backend,
+    // Whatever the client sent.
+    Preserve,
+    Fixed(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenerMode {
+    Http,
+    // Bytes are relayed to a backend of the pool without being parsed.
+    Tcp,
+    // Datagrams are relayed per client address.
+    Udp,
+    // TLS is relayed undecrypted; the ClientHello's SNI picks the pool.
+    Passthrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientCert {
+    // Clients without a certificate issued by client_ca are turned away in
+    // the handshake.
+    Required,
+    // Clients without one are let in too.
+    Optional,
+    // Without client_ca: any certificate is asked for, and routes with
+    // client_ca check it.
+    Request,
+}
+
+// An extra listening socket, next to the HTTP one on 127.0.0.1:8080.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub bind: [u8; 6],
+    pub mode: ListenerMode,
+    // Required for TCP and UDP listeners, and for passthrough ones without
+    // sni rules; for HTTP ones it replaces the default pool.
+    pub pool: Option<String>,
+    // PEM files; with a certificate an HTTP listener terminates TLS.
+    // tls_cert_dir holds <name>.crt/<name>.key pairs picked by SNI, and
+    // tls_cert/tls_key is the fallback.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_cert_dir: Option<String>,
+    pub alpn: Vec<String>,
+    // Clients are asked for a certificate issued by client_ca.
+    pub client_ca: Option<String>,
+    pub client_cert: ClientCert,
+    // Built from the settings above once the config is loaded.
+    pub tls: Option<Arc<ServerConfig>>,
+    // Passthrough listeners: the first rule matching the SNI picks the pool,
+    // and `pool` takes the rest.
+    pub sni_pools: Vec<(Vec<HostPattern>, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    // Backends as [a, b, c, d, port_hi, port_lo].
+    pub servers: Vec<[u8; 6]>,
+    pub host_header: HostHeader,
+    pub request_headers: Vec<HeaderRule>,
+    pub response_headers: Vec<HeaderRule>,
+    // Backend connections use TLS when `tls` is set. tls_server_name is sent
+    // as SNI and checked against the certificate instead of the backend's IP,
+    // and tls_client_cert/tls_client_key is shown to backends that ask.
+    pub tls: bool,
+    pub tls_ca: Option<String>,
+    pub tls_verify: bool,
+    pub tls_server_name: Option<String>,
+    pub tls_client_cert: Option<String>,
+    pub tls_client_key: Option<String>,
+    // The rustls client side of those settings, made during validation.
+    pub tls_config: Option<Arc<ClientConfig>>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            servers: Vec::new(),
+            host_header: HostHeader::Backend,
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            tls: false,
+            tls_ca: None,
+            tls_verify: true,
+            tls_server_name: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            tls_config: None,
+        }
+    }
+}
+
+// An address block such as 10.0.0.0/8 or fd00::/8.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid address in {}", value))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.trim().parse::<u8>() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(format!("invalid prefix length in {}", value)),
+            },
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub max_connect_retries: u32,
+    pub backend_timeout_ms: u64,
+    pub retry_after_secs: u32,
+    pub error_content_type: String,
+    pub error_pages: HashMap<u16, Vec<u8>>,
+    pub keepalive_timeout_ms: u64,
+    pub keepalive_max_requests: u32,
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout_ms: u64,
+    pub max_request_body_bytes: u64,
+    pub max_request_header_bytes: usize,
+    pub header_timeout_ms: u64,
+    pub tunnel_idle_timeout_ms: u64,
+    pub udp_flow_timeout_ms: u64,
+    pub tls_reload_ms: u64,
+    pub trusted_proxies: Vec<Cidr>,
+    pub forwarded_header: bool,
+    pub pools: HashMap<String, PoolConfig>,
+    // Pool names in the order they were defined; a pool's position is its id
+    // in messages to conn_db.
+    pub pool_names: Vec<String>,
+    pub routes: Vec<Route>,
+    pub listeners: Vec<ListenerConfig>,
+    // Name added to Via headers; empty leaves Via alone.
+    pub via: String,
+    // Carries a verified client certificate to backends. It is taken out of
+    // every request, so clients cannot make it up.
+    pub client_cert_header: Option<HeaderName>,
+    // Answer 421 for hosts no route serves instead of using the default pool.
+    pub reject_unknown_hosts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_connect_retries: 3,
+            backend_timeout_ms: 30000,
+            retry_after_secs: 5,
+            error_content_type: String::from("text/html"),
+            error_pages: HashMap::new(),
+            keepalive_timeout_ms: 5000,
+            keepalive_max_requests: 100,
+            pool_max_idle: 8,
+            pool_idle_timeout_ms: 30000,
+            max_request_body_bytes: 10 * 1024 * 1024,
+            max_request_header_bytes: 16384,
+            header_timeout_ms: 10000,
+            tunnel_idle_timeout_ms: 300000,
+            udp_flow_timeout_ms: 30000,
+            tls_reload_ms: 10000,
+            trusted_proxies: Vec::new(),
+            forwarded_header: false,
+            pools: HashMap::from([(
+                DEFAULT_POOL.to_string(),
+                PoolConfig {
+                    servers: (3000..3011u16)
+                        .map(|port| [127, 0, 0, 1, (port >> 8) as u8, port as u8])
+                        .collect(),
+                    ..PoolConfig::default()
+                },
+            )]),
+            pool_names: vec![DEFAULT_POOL.to_string()],
+            routes: Vec::new(),
+            listeners: Vec::new(),
+            via: String::new(),
+            client_cert_header: None,
+            reject_unknown_hosts: false,
+        }
+    }
+}
+
+fn parse_u32(key: &str, value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("invalid value for {}: {}", key, value))
+}
+
+fn parse_u64(key: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse::<u64>()
+        .map_err(|_| format!("invalid value for {}: {}", key, value))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(format!("invalid value for {}: {}", key, value)),
+    }
+}
+
+fn parse_host_header(key: &str, value: &str) -> Result<HostHeader, String> {
+    match value.split_once(' ') {
+        Some(("rewrite", host)) if !host.trim().is_empty() => Ok(HostHeader::Fixed(host.trim().to_string())),
+        _ => match value {
+            "backend" => Ok(HostHeader::Backend),
+            "preserve" => Ok(HostHeader::Preserve),
+            _ => Err(format!("invalid value for {}: {}", key, value)),
+        },
+    }
+}
+
+fn parse_servers(key: &str, value: &str) -> Result<Vec<[u8; 6]>, String> {
+    let mut servers: Vec<[u8; 6]> = Vec::new();
+    for addr in value.split(',').map(|addr| addr.trim()).filter(|addr| !addr.is_empty()) {
+        let addr: SocketAddrV4 = addr
+            .parse()
+            .map_err(|_| format!("invalid server in {}: {}", key, addr))?;
+        let mut server = [0u8; 6];
+        server[..4].copy_from_slice(&addr.ip().octets());
+        server[4..].copy_from_slice(&addr.port().to_be_bytes());
+        if !servers.contains(&server) {
+            servers.push(server);
+        }
+    }
+    Ok(servers)
+}
+
+fn parse_listener_setting(listener: &mut ListenerConfig, key: &str, setting: &str, value: &str) -> Result<bool, String> {
+    match setting {
+        "bind" => {
+            listener.bind = match parse_servers(key, value)?.as_slice() {
+                [bind] => *bind,
+                _ => return Err(format!("expected one ip:port for {}", key)),
+            };
+        }
+        "mode" => {
+            listener.mode = match value {
+                "http" => ListenerMode::Http,
+                "tcp" => ListenerMode::Tcp,
+                "udp" => ListenerMode::Udp,
+                "passthrough" => ListenerMode::Passthrough,
+                _ => return Err(format!("invalid value for {}: {}", key, value)),
+            };
+        }
+        "pool" => listener.pool = Some(value.to_string()),
+        "tls_cert" => listener.tls_cert = Some(value.to_string()),
+        "tls_key" => listener.tls_key = Some(value.to_string()),
+        "tls_cert_dir" => listener.tls_cert_dir = Some(value.to_string()),
+        "client_ca" => listener.client_ca = Some(value.to_string()),
+        "client_cert" => {
+            listener.client_cert = match value {
+                "required" => ClientCert::Required,
+                "optional" => ClientCert::Optional,
+                "request" => ClientCert::Request,
+                _ => return Err(format!("invalid value for {}: {}", key, value)),
+            };
+        }
+        // sni = <pool> <host>, <host>; repeatable.
+        "sni" => {
+            let (pool, hosts) = value
+                .split_once(char::is_whitespace)
+                .ok_or(format!("expected <pool> <hosts> for {}", key))?;
+            let hosts = hosts
+                .split(',')
+                .map(|host| host.trim())
+                .filter(|host| !host.is_empty())
+                .map(HostPattern::parse)
+                .collect();
+            listener.sni_pools.push((hosts, pool.to_string()));
+        }
+        "alpn" => {
+            listener.alpn = value
+                .split(',')
+                .map(|protocol| protocol.trim())
+                .filter(|protocol| !protocol.is_empty())
+                .map(|protocol| match protocol {
+                    // Requests are only ever parsed as HTTP/1.x.
+                    "http/1.1" | "http/1.0" => Ok(protocol.to_string()),
+                    _ => Err(format!("unsupported protocol in {}: {}", key, protocol)),
+                })
+                .collect::<Result<Vec<String>, String>>()?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_regex(key: &str, value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|e| format!("invalid regex for {}: {}", key, e))
+}
+
+fn parse_route_setting(route: &mut Route, key: &str, setting: &str, value: &str) -> Result<bool, String> {
+    match setting {
+        "pool" => route.pool = value.to_string(),
+        "host" => {
+            route.hosts = value
+                .split(',')
+                .map(|host| host.trim())
+                .filter(|host| !host.is_empty())
+                .map(HostPattern::parse)
+                .collect();
+        }
+        "method" => {
+            route.methods = value
+                .split(',')
+                .map(|method| method.trim())
+                .filter(|method| !method.is_empty())
+                .map(|method| {
+                    Method::from_bytes(method.as_bytes()).map_err(|_| format!("invalid method in {}: {}", key, method))
+                })
+                .collect::<Result<Vec<Method>, String>>()?;
+        }
+        "path_prefix" => route.path_prefix = Some(value.to_string()),
+        "scheme" => {
+            if value != "http" && value != "https" {
+                return Err(format!("invalid value for {}: {}", key, value));
+            }
+            route.scheme = Some(value.to_string());
+        }
+        "path_regex" => route.path_regex = Some(parse_regex(key, value)?),
+        // Repeatable; every condition has to match.
+        "header" | "cookie" | "query" => route.attributes.push(AttributeMatch::parse(setting, value)?),
+        "strip_prefix" => route.strip_prefix = parse_bool(key, value)?,
+        "rewrite" => {
+            let (pattern, replacement) = value
+                .split_once(char::is_whitespace)
+                .ok_or(format!("expected <regex> <replacement> for {}", key))?;
+            route.rewrite = Some((parse_regex(key, pattern)?, replacement.trim().to_string()));
+        }
+        "request_header" => route.request_headers.push(HeaderRule::parse(value)?),
+        "response_header" => route.response_headers.push(HeaderRule::parse(value)?),
+        "client_ca" => route.client_ca = Some(Arc::new(load_roots(Path::new(value))?)),
+        // redirect = <301|302|303|307|308> <location>
+        "redirect" => {
+            let (status, location) = value
+                .split_once(char::is_whitespace)
+                .ok_or(format!("expected <status> <location> for {}", key))?;
+            let status = match status.parse::<u16>() {
+                Ok(status) if [301, 302, 303, 307, 308].contains(&status) => status,
+                _ => return Err(format!("invalid redirect status in {}: {}", key, status)),
+            };
+            route.action = RouteAction::Redirect(status, location.trim().to_string());
+        }
+        // respond = <status> <content type> [body]
+        // respond_file = <status> <content type> <file>
+        "respond" | "respond_file" => {
+            let mut parts = value.splitn(3, char::is_whitespace);
+            let status = match parts.next().unwrap_or_default().parse::<u16>() {
+                Ok(status) if (200..600).contains(&status) => status,
+                _ => return Err(format!("invalid status in {}", key)),
+            };
+            let content_type = parts
+                .next()
+                .filter(|content_type| !content_type.is_empty())
+                .ok_or(format!("missing content type in {}", key))?
+                .to_string();
+            let rest = parts.next().map(|rest| rest.trim()).unwrap_or_default();
+            let body = if setting == "respond_file" {
+                fs::read(rest).map_err(|e| format!("failed to read {}: {}", rest, e))?
+            } else {
+                rest.as_bytes().to_vec()
+            };
+            // These statuses never carry a body (1xx is already refused above).
+            if matches!(status, 204 | 304) && !body.is_empty() {
+                return Err(format!("{} cannot have a body with status {}", key, status));
+            }
+            route.action = RouteAction::Respond { status, content_type, body };
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_status(key: &str, code: &str) -> Result<u16, String> {
+    match code.parse::<u16>() {
+        Ok(code) if (400..600).contains(&code) => Ok(code),
+        _ => Err(format!("invalid status code in {}", key)),
+    }
+}
+
+impl Config {
+    // Settings for the named pool; pools without any settings use the defaults.
+    pub fn pool(&self, name: &str) -> &PoolConfig {
+        static DEFAULT: LazyLock<PoolConfig> = LazyLock::new(PoolConfig::default);
+        self.pools.get(name).unwrap_or(&DEFAULT)
+    }
+
+    pub fn pool_id(&self, name: &str) -> u8 {
+        self.pool_names.iter().position(|pool| pool == name).unwrap_or(0) as u8
+    }
+
+    // Lines are `key = value`; blank lines and lines starting with `#` are
+    // ignored. A missing file gives the default configuration.
+    pub fn load(path: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(config),
+        };
+
+        for (line_no, raw_line) in contents.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(format!("line {}: expected key = value", line_no + 1))?;
+            let key = key.trim();
+            let value = value.trim();
+
+            match key.split_once('.') {
+                // error_page.<status> = <file> and error_body.<status> = <text>
+                Some(("error_page", code)) => {
+                    let page = fs::read(value)
+                        .map_err(|e| format!("failed to read error page {}: {}", value, e))?;
+                    config.error_pages.insert(parse_status(key, code)?, page);
+                }
+                Some(("error_body", code)) => {
+                    config
+                        .error_pages
+                        .insert(parse_status(key, code)?, value.as_bytes().to_vec());
+                }
+                // pool.<name>.<setting> = <value>
+                Some(("pool", rest)) => {
+                    let (name, setting) = rest
+                        .rsplit_once('.')
+                        .ok_or(format!("line {}: expected pool.<name>.<setting>", line_no + 1))?;
+                    if !config.pool_names.iter().any(|pool| pool == name) {
+                        config.pool_names.push(name.to_string());
+                    }
+                    let pool = config.pools.entry(name.to_string()).or_default();
+                    match setting {
+                        "servers" => pool.servers = parse_servers(key, value)?,
+                        "host_header" => pool.host_header = parse_host_header(key, value)?,
+                        // Rules accumulate and run in the order they are listed.
+                        "request_header" => pool.request_headers.push(HeaderRule::parse(value)?),
+                        "response_header" => pool.response_headers.push(HeaderRule::parse(value)?),
+                        "tls" => pool.tls = parse_bool(key, value)?,
+                        "tls_ca" => pool.tls_ca = Some(value.to_string()),
+                        "tls_verify" => pool.tls_verify = parse_bool(key, value)?,
+                        "tls_server_name" => pool.tls_server_name = Some(value.to_string()),
+                        "tls_client_cert" => pool.tls_client_cert = Some(value.to_string()),
+                        "tls_client_key" => pool.tls_client_key = Some(value.to_string()),
+                        _ => return Err(format!("line {}: unknown key {}", line_no + 1, key)),
+                    }
+                }
+                // route.<name>.<setting> = <value>; routes are tried in the
+                // order they first appear.
+                Some(("route", rest)) => {
+                    let (name, setting) = rest
+                        .rsplit_once('.')
+                        .ok_or(format!("line {}: expected route.<name>.<setting>", line_no + 1))?;
+                    let index = match config.routes.iter().position(|route| route.name == name) {
+                        Some(index) => index,
+                        None => {
+                            config.routes.push(Route::new(name));
+                            config.routes.len() - 1
+                        }
+                    };
+                    if !parse_route_setting(&mut config.routes[index], key, setting, value)
+                        .map_err(|e| format!("line {}: {}", line_no + 1, e))?
+                    {
+                        return Err(format!("line {}: unknown key {}", line_no + 1, key));
+                    }
+                }
+                // listener.<name>.<setting> = <value>
+                Some(("listener", rest)) => {
+                    let (name, setting) = rest
+                        .rsplit_once('.')
+                        .ok_or(format!("line {}: expected listener.<name>.<setting>", line_no + 1))?;
+                    let index = match config.listeners.iter().position(|listener| listener.name == name) {
+                        Some(index) => index,
+                        None => {
+                            config.listeners.push(ListenerConfig {
+                                name: name.to_string(),
+                                bind: [0u8; 6],
+                                mode: ListenerMode::Http,
+                                pool: None,
+                                tls_cert: None,
+                                tls_key: None,
+                                tls_cert_dir: None,
+                                alpn: vec![String::from("http/1.1")],
+                                client_ca: None,
+                                client_cert: ClientCert::Required,
+                                tls: None,
+                                sni_pools: Vec::new(),
+                            });
+                            config.listeners.len() - 1
+                        }
+                    };
+                    if !parse_listener_setting(&mut config.listeners[index], key, setting, value)
+                        .map_err(|e| format!("line {}: {}", line_no + 1, e))?
+                    {
+                        return Err(format!("line {}: unknown key {}", line_no + 1, key));
+                    }
+                }
+                _ => match key {
+                    "max_connect_retries" => config.max_connect_retries = parse_u32(key, value)?,
+                    "backend_timeout_ms" => config.backend_timeout_ms = parse_u64(key, value)?,
+                    "retry_after_secs" => config.retry_after_secs = parse_u32(key, value)?,
+                    "error_content_type" => config.error_content_type = value.to_string(),
+                    "keepalive_timeout_ms" => config.keepalive_timeout_ms = parse_u64(key, value)?,
+                    "keepalive_max_requests" => config.keepalive_max_requests = parse_u32(key, value)?,
+                    "pool_max_idle" => config.pool_max_idle = parse_u32(key, value)? as usize,
+                    "pool_idle_timeout_ms" => config.pool_idle_timeout_ms = parse_u64(key, value)?,
+                    "max_request_body_bytes" => config.max_request_body_bytes = parse_u64(key, value)?,
+                    "max_request_header_bytes" => config.max_request_header_bytes = parse_u32(key, value)? as usize,
+                    "header_timeout_ms" => config.header_timeout_ms = parse_u64(key, value)?,
+                    "tunnel_idle_timeout_ms" => config.tunnel_idle_timeout_ms = parse_u64(key, value)?,
+                    "udp_flow_timeout_ms" => config.udp_flow_timeout_ms = parse_u64(key, value)?,
+                    "tls_reload_ms" => config.tls_reload_ms = parse_u64(key, value)?,
+                    "trusted_proxies" => {
+                        config.trusted_proxies = value
+                            .split(',')
+                            .map(|cidr| cidr.trim())
+                            .filter(|cidr| !cidr.is_empty())
+                            .map(Cidr::parse)
+                            .collect::<Result<Vec<Cidr>, String>>()?;
+                    }
+                    "via" => config.via = value.to_string(),
+                    "client_cert_header" => {
+                        config.client_cert_header = Some(
+                            HeaderName::from_bytes(value.as_bytes())
+                                .map_err(|_| format!("invalid value for {}: {}", key, value))?,
+                        );
+                    }
+                    "unknown_host" => {
+                        config.reject_unknown_hosts = match value {
+                            "default" => false,
+                            "reject" => true,
+                            _ => return Err(format!("line {}: invalid value for {}: {}", line_no + 1, key, value)),
+                        }
+                    }
+                    "forwarded_header" => config.forwarded_header = parse_bool(key, value)?,
+                    _ => return Err(format!("line {}: unknown key {}", line_no + 1, key)),
+                },
+            }
+        }
+
+        if config.pool_names.len() > 256 {
+            return Err(String::from("at most 256 pools can be defined"));
+        }
+        for route in &config.routes {
+            let proxies = matches!(route.action, RouteAction::Proxy);
+            if proxies && route.pool.is_empty() {
+                return Err(format!("route {} has no pool", route.name));
+            }
+            if !proxies && !route.pool.is_empty() {
+                return Err(format!("route {} sets a pool and a redirect or response", route.name));
+            }
+            if proxies && !config.pools.contains_key(&route.pool) {
+                return Err(format!("route {} uses unknown pool {}", route.name, route.pool));
+            }
+            if route.strip_prefix && route.path_prefix.is_none() {
+                return Err(format!("route {} sets strip_prefix without path_prefix", route.name));
+            }
+        }
+
+        for (name, pool) in config.pools.iter_mut() {
+            if !pool.tls {
+                if pool.tls_ca.is_some()
+                    || !pool.tls_verify
+                    || pool.tls_server_name.is_some()
+                    || pool.tls_client_cert.is_some()
+                    || pool.tls_client_key.is_some()
+                {
+                    return Err(format!("pool {} has TLS settings but not tls = true", name));
+                }
+                continue;
+            }
+            pool.tls_config = Some(client_config(pool).map_err(|e| format!("pool {}: {}", name, e))?);
+        }
+
+        let tls_reload_ms = config.tls_reload_ms;
+        for listener in &mut config.listeners {
+            if listener.bind[4..] == [0, 0] {
+                return Err(format!("listener {} has no bind address", listener.name));
+            }
+            match &listener.pool {
+                Some(pool) if !config.pools.contains_key(pool) => {
+                    return Err(format!("listener {} uses unknown pool {}", listener.name, pool));
+                }
+                None if listener.mode == ListenerMode::Passthrough && !listener.sni_pools.is_empty() => {}
+                None if listener.mode != ListenerMode::Http => {
+                    return Err(format!("listener {} has no pool", listener.name));
+                }
+                _ => {}
+            }
+            if !listener.sni_pools.is_empty() && listener.mode != ListenerMode::Passthrough {
+                return Err(format!("listener {} sets sni but is not a passthrough listener", listener.name));
+            }
+            for (_, pool) in &listener.sni_pools {
+                if !config.pools.contains_key(pool) {
+                    return Err(format!("listener {} uses unknown pool {}", listener.name, pool));
+                }
+            }
+            if listener.mode == ListenerMode::Passthrough {
+                // The client's TLS goes to the backend as is.
+                let pools = listener.sni_pools.iter().map(|(_, pool)| pool).chain(listener.pool.as_ref());
+                if let Some(pool) = pools.into_iter().find(|pool| config.pools[*pool].tls) {
+                    return Err(format!("listener {} is a passthrough listener and cannot use TLS pool {}", listener.name, pool));
+                }
+            }
+            if listener.tls_cert.is_some() || listener.tls_key.is_some() || listener.tls_cert_dir.is_some() {
+                if listener.mode != ListenerMode::Http {
+                    return Err(format!("listener {} is not an http listener and cannot use TLS", listener.name));
+                }
+                if listener.client_ca.is_some() && listener.client_cert == ClientCert::Request {
+                    return Err(format!("listener {} has client_ca, so client_cert = request does not apply", listener.name));
+                }
+                let tls = server_config(listener, tls_reload_ms)
+                    .map_err(|e| format!("listener {}: {}", listener.name, e))?;
+                listener.tls = Some(tls);
+            } else if listener.client_ca.is_some() || listener.client_cert != ClientCert::Required {
+                return Err(format!("listener {} checks client certificates but does not use TLS", listener.name));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, contents: &str) -> Result<Config, String> {
+        let path = std::env::temp_dir().join(format!("lb-config-test-{}-{}.txt", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn listener_mode_defaults_to_http() {
+        let config = load("mode", "listener.web.bind = 127.0.0.1:8081\nlistener.db.bind = 127.0.0.1:5433\nlistener.db.mode = tcp\nlistener.db.pool = default\n").unwrap();
+        assert_eq!(config.listeners[0].mode, ListenerMode::Http);
+        assert_eq!(config.listeners[1].mode, ListenerMode::Tcp);
+        assert!(load("mode-pool", "listener.db.bind = 127.0.0.1:5433\nlistener.db.mode = tcp\n").is_err());
+    }
+
+    fn respond(setting: &str, value: &str) -> Result<bool, String> {
+        parse_route_setting(&mut Route::new("test"), setting, setting, value)
+    }
+
+    #[test]
+    fn respond_body_needs_a_status_that_allows_one() {
+        assert!(respond("respond", "204 text/plain").is_ok());
+        assert!(respond("respond", "304 text/plain").is_ok());
+        assert!(respond("respond", "200 text/plain ok").is_ok());
+        assert!(respond("respond", "204 text/plain gone").is_err());
+        assert!(respond("respond", "304 text/plain stale").is_err());
+        assert!(respond("respond", "101 text/plain").is_err());
+        assert!(respond("respond_file", "204 text/plain Cargo.toml").is_err());
+    }
+
+    fn contains(cidr: &str, ip: &str) -> bool {
+        Cidr::parse(cidr).unwrap().contains(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn cidr_v4() {
+        assert!(contains("10.0.0.0/8", "10.255.255.255"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.0"));
+        assert!(contains("192.168.1.0/24", "192.168.1.77"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.1"));
+        // Host bits in the network address are ignored.
+        assert!(contains("192.168.1.9/24", "192.168.1.200"));
+    }
+
+    #[test]
+    fn cidr_v4_prefix_edges() {
+        assert!(contains("0.0.0.0/0", "203.0.113.5"));
+        assert!(contains("203.0.113.5/32", "203.0.113.5"));
+        assert!(!contains("203.0.113.5/32", "203.0.113.4"));
+        assert!(contains("203.0.113.5", "203.0.113.5"));
+        assert!(!contains("203.0.113.5", "203.0.113.6"));
+        assert!(contains("128.0.0.0/1", "255.0.0.1"));
+        assert!(!contains("128.0.0.0/1", "127.255.255.255"));
+        assert!(contains("10.0.0.0/31", "10.0.0.1"));
+        assert!(!contains("10.0.0.0/31", "10.0.0.2"));
+    }
+
+    #[test]
+    fn cidr_v6() {
+        assert!(contains("fd00::/8", "fdab::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(contains("8000::/1", "ffff::1"));
+        assert!(!contains("8000::/1", "7fff::1"));
+    }
+
+    #[test]
+    fn cidr_families_do_not_mix() {
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("::/0", "127.0.0.1"));
+        assert!(!contains("::ffff:0:0/96", "10.0.0.1"));
+    }
+
+    #[test]
+    fn cidr_rejects_bad_input() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("example.com").is_err());
+    }
+}
@@ -1,12 +1,15 @@
 use libc::*;
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::mem::{self, zeroed};
 use std::ptr;
 use std::sync::{Arc, Mutex};
 
+use crate::config::Config;
 use crate::least_conn_server::LCS;
 
 const SOCK_PATH: &str = "/tmp/test1.sock";
+const MSG_LEN: usize = 7;
 
 fn ev_set(
     kev: &mut libc::kevent,
@@ -25,7 +28,7 @@ fn ev_set(
     kev.udata = udata;
 }
 
-pub fn manage_connections(worker_count: usize) {
+pub fn manage_connections(worker_count: usize, config: &Config) {
     unsafe {
         let sock_fd = socket(AF_UNIX, SOCK_STREAM, 0);
         if sock_fd < 0 {
@@ -53,28 +56,23 @@ pub fn manage_connections(worker_count: usize) {
 
         println!("Server listening on {}", SOCK_PATH);
 
-        let server_lcs = Arc::new(Mutex::new(LCS::new()));
+        // One LCS per pool, indexed by the pool id workers send. A server in
+        // several pools has its connections counted in each of them.
+        let server_lcs = Arc::new(Mutex::new(Vec::<LCS>::new()));
         let server_lcs_data = Arc::clone(&server_lcs);
         {
             let mut data = server_lcs_data.lock().unwrap();
-            let servers: Vec<[u8; 6]> = vec![
-                [127, 0, 0, 1, 11, 184],
-                [127, 0, 0, 1, 11, 185],
-                [127, 0, 0, 1, 11, 186],
-                [127, 0, 0, 1, 11, 187],
-                [127, 0, 0, 1, 11, 188],
-                [127, 0, 0, 1, 11, 189],
-                [127, 0, 0, 1, 11, 190],
-                [127, 0, 0, 1, 11, 191],
-                [127, 0, 0, 1, 11, 192],
-                [127, 0, 0, 1, 11, 193],
-                [127, 0, 0, 1, 11, 194],
-            ];
-            for server in &servers {
-                data.insert(&server).expect("Insert failed");
+            for name in &config.pool_names {
+                let mut lcs = LCS::new();
+                for server in &config.pool(name).servers {
+                    lcs.insert(server).expect("Insert failed");
+                }
+                data.push(lcs);
             }
         }
 
+        let mut pending: HashMap<i32, Vec<u8>> = HashMap::new();
+
         let kq = kqueue();
         let mut ev: kevent = zeroed();
         ev_set(
@@ -133,9 +131,10 @@ pub fn manage_connections(worker_count: usize) {
                             //     client_fd,
                             //     std::process::id()
                             // );
-                            let mut buf = [0u8; 21];
-                            let n = read(client_fd, buf.as_mut_ptr() as *mut _, 4096);
+                            let mut buf = [0u8; 4096];
+                            let n = read(client_fd, buf.as_mut_ptr() as *mut _, buf.len());
                             if n == 0 {
+                                pending.remove(&client_fd);
                                 let mut ev: kevent = mem::zeroed();
                                 ev_set(
                                     &mut ev,
@@ -154,40 +153,60 @@ pub fn manage_connections(worker_count: usize) {
                                     eprintln!("Failed to delete fd {} from kqueue", client_fd);
                                 }
                             } else if n > 0 {
-                                let req_type = buf[0];
-                                let server: [u8; 6] = buf[1..7]
-                                    .try_into()
-                                    .expect("something went wrong in slicing");
-                                match req_type {
-                                    0 => {
-                                        let mut response = [0u8; 10];
-                                        let server = data.get_least_conn_server().unwrap();
-                                        response[..6].copy_from_slice(&server);
-                                        response[6..].copy_from_slice(&buf[3..7]);
-
-                                        // let stats = data.get_stats().unwrap();
-                                        // for (key, val) in stats {
-                                        //     println!("{:?} : {}", key, val);
-                                        // }
-                                        write(client_fd, response.as_ptr() as *const _, response.len());
-                                        let _ = data.server_conn_increament(&server);
-                                    }
-                                    1 => {
-                                        let _ = data.server_conn_decreament(&server);
-                                        // let stats = data.get_stats().unwrap();
-                                        // for (key, val) in stats {
-                                        //     println!("{:?} : {}", key, val);
-                                        // }
-                                    }
-                                    2 => {
-                                        let _ = data.delete(&server);
-                                        // let stats = data.get_stats().unwrap();
-                                        // for (key, val) in stats {
-                                        //     println!("{:?} : {}", key, val);
-                                        // }
-                                    }
-                                    _ => {}
-                                };
+                                // A worker can write several requests back to back, so a
+                                // single read may hold more than one message.
+                                let msgs = pending.entry(client_fd).or_default();
+                                msgs.extend_from_slice(&buf[..n as usize]);
+                                let msg_count = msgs.len() / MSG_LEN;
+                                for msg in msgs.drain(..msg_count * MSG_LEN).collect::<Vec<u8>>().chunks(MSG_LEN) {
+                                    let req_type = msg[0];
+                                    let server: [u8; 6] = msg[1..7]
+                                        .try_into()
+                                        .expect("something went wrong in slicing");
+                                    match req_type {
+                                        0 => {
+                                            // An all-zero server tells the worker that no
+                                            // backend is available.
+                                            let mut response = [0u8; 10];
+                                            let server = data
+                                                .get(msg[1] as usize)
+                                                .and_then(|pool| pool.get_least_conn_server().ok())
+                                                .unwrap_or([0u8; 6]);
+                                            response[..6].copy_from_slice(&server);
+                                            response[6..].copy_from_slice(&msg[3..7]);
+
+                                            // let stats = data.get_stats().unwrap();
+                                            // for (key, val) in stats {
+                                            //     println!("{:?} : {}", key, val);
+                                            // }
+                                            write(client_fd, response.as_ptr() as *const _, response.len());
+                                            if server != [0u8; 6] {
+                                                for pool in data.iter_mut() {
+                                                    let _ = pool.server_conn_increament(&server);
+                                                }
+                                            }
+                                        }
+                                        1 => {
+                                            for pool in data.iter_mut() {
+                                                let _ = pool.server_conn_decreament(&server);
+                                            }
+                                            // let stats = data.get_stats().unwrap();
+                                            // for (key, val) in stats {
+                                            //     println!("{:?} : {}", key, val);
+                                            // }
+                                        }
+                                        2 => {
+                                            for pool in data.iter_mut() {
+                                                let _ = pool.delete(&server);
+                                            }
+                                            // let stats = data.get_stats().unwrap();
+                                            // for (key, val) in stats {
+                                            //     println!("{:?} : {}", key, val);
+                                            // }
+                                        }
+                                        _ => {}
+                                    };
+                                }
                             } else {
                                 let mut ev: kevent = mem::zeroed();
                                 ev_set(
@@ -0,0 +1,145 @@
+use std::net::IpAddr;
+
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+use crate::config::Config;
+
+fn is_trusted(ip: &IpAddr, config: &Config) -> bool {
+    config.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+}
+
+fn forwarded_node(ip: &IpAddr) -> String {
+    // IPv6 addresses must be bracketed and quoted (RFC 7239 section 6).
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+fn set_header(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+// Adds X-Forwarded-*, X-Real-IP and optionally Forwarded for a request from
+// `peer` that arrived over `proto` ("http" or "https"). Values a client
+// sent are only kept when `peer` is a trusted proxy; anyone else could put
+// whatever they like in them.
+pub fn add_forwarded_headers(headers: &mut HeaderMap, peer: IpAddr, local_port: u16, proto: &str, config: &Config) {
+    let trusted = is_trusted(&peer, config);
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let forwarded_for = if trusted {
+        header_str(headers, &HeaderName::from_static("x-forwarded-for"))
+    } else {
+        None
+    };
+    let chain = match forwarded_for {
+        Some(chain) => format!("{}, {}", chain, peer),
+        None => peer.to_string(),
+    };
+    set_header(headers, "x-forwarded-for", &chain);
+
+    // The client is the right-most address not added by a trusted proxy.
+    let real_ip = chain
+        .rsplit(',')
+        .map(|addr| addr.trim())
+        .find(|addr| !addr.parse::<IpAddr>().is_ok_and(|ip| is_trusted(&ip, config)))
+        .or_else(|| chain.split(',').next().map(|addr| addr.trim()))
+        .unwrap_or_default()
+        .to_string();
+    if !(trusted && headers.contains_key("x-real-ip")) {
+        set_header(headers, "x-real-ip", &real_ip);
+    }
+
+    if !(trusted && headers.contains_key("x-forwarded-proto")) {
+        set_header(headers, "x-forwarded-proto", proto);
+    }
+    if !(trusted && headers.contains_key("x-forwarded-host")) {
+        match &host {
+            Some(host) => set_header(headers, "x-forwarded-host", host),
+            None => {
+                headers.remove("x-forwarded-host");
+            }
+        }
+    }
+    if !(trusted && headers.contains_key("x-forwarded-port")) {
+        set_header(headers, "x-forwarded-port", &local_port.to_string());
+    }
+
+    // A trusted proxy's Forwarded passes through either way; the setting
+    // only decides whether we add our own element.
+    if !trusted {
+        headers.remove(header::FORWARDED);
+    }
+    if config.forwarded_header {
+        let mut element = format!("for={};proto={}", forwarded_node(&peer), proto);
+        if let Some(host) = &host {
+            element.push_str(&format!(";host=\"{}\"", host.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+        let value = match header_str(headers, &header::FORWARDED) {
+            Some(previous) => format!("{}, {}", previous, element),
+            None => element,
+        };
+        set_header(headers, "forwarded", &value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cidr;
+
+    fn config(forwarded_header: bool) -> Config {
+        Config {
+            trusted_proxies: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+            forwarded_header,
+            ..Config::default()
+        }
+    }
+
+    fn forwarded(peer: &str, forwarded_header: bool) -> Vec<String> {
+        let mut headers = HeaderMap::new();
+        headers.append(header::FORWARDED, HeaderValue::from_static("for=192.0.2.1"));
+        headers.append(header::FORWARDED, HeaderValue::from_static("for=192.0.2.2;proto=https"));
+        add_forwarded_headers(&mut headers, peer.parse().unwrap(), 80, "http", &config(forwarded_header));
+        headers
+            .get_all(header::FORWARDED)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn trusted_forwarded_passes_through_untouched() {
+        assert_eq!(forwarded("10.1.2.3", false), ["for=192.0.2.1", "for=192.0.2.2;proto=https"]);
+    }
+
+    #[test]
+    fn trusted_forwarded_is_appended_to() {
+        assert_eq!(forwarded("10.1.2.3", true), ["for=192.0.2.1, for=192.0.2.2;proto=https, for=10.1.2.3;proto=http"]);
+    }
+
+    #[test]
+    fn untrusted_forwarded_is_replaced_or_removed() {
+        assert_eq!(forwarded("203.0.113.9", true), ["for=203.0.113.9;proto=http"]);
+        assert!(forwarded("203.0.113.9", false).is_empty());
+    }
+}
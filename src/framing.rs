@@ -0,0 +1,444 @@
+use http::{HeaderValue, Request, Response, Version, header};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkState {
+    Size,
+    Extension,
+    SizeLf,
+    Data,
+    DataCr,
+    DataLf,
+    TrailerStart,
+    TrailerLine,
+    TrailerLf,
+    FinalLf,
+    Done,
+}
+
+const MAX_TRAILER_BYTES: usize = 16384;
+
+// Follows a chunked body byte by byte so we know where it ends. Chunk data
+// can optionally be copied out, which decodes the body; trailer lines are
+// kept so they can be re-encoded.
+#[derive(Debug, Clone)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    size: u64,
+    size_digits: usize,
+    remaining: u64,
+    pub trailers: Vec<u8>,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+            size: 0,
+            size_digits: 0,
+            remaining: 0,
+            trailers: Vec::new(),
+        }
+    }
+
+    // Returns how many bytes of `data` belong to the body and whether the
+    // terminating chunk and trailers have been seen.
+    pub fn advance(&mut self, data: &[u8], mut out: Option<&mut Vec<u8>>) -> Result<(usize, bool), &'static str> {
+        let mut i = 0;
+        while i < data.len() && self.state != ChunkState::Done {
+            let byte = data[i];
+            match self.state {
+                ChunkState::Size => match byte {
+                    b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                        let digit = (byte as char).to_digit(16).unwrap() as u64;
+                        self.size = self
+                            .size
+                            .checked_mul(16)
+                            .and_then(|size| size.checked_add(digit))
+                            .ok_or("Chunk size too large")?;
+                        self.size_digits += 1;
+                    }
+                    b';' | b' ' | b'\t' if self.size_digits > 0 => self.state = ChunkState::Extension,
+                    b'\r' if self.size_digits > 0 => self.state = ChunkState::SizeLf,
+                    _ => return Err("Invalid chunk size"),
+                },
+                ChunkState::Extension => {
+                    if byte == b'\r' {
+                        self.state = ChunkState::SizeLf;
+                    }
+                }
+                ChunkState::SizeLf => {
+                    if byte != b'\n' {
+                        return Err("Invalid chunk size line");
+                    }
+                    if self.size == 0 {
+                        self.state = ChunkState::TrailerStart;
+                    } else {
+                        self.remaining = self.size;
+                        self.state = ChunkState::Data;
+                    }
+                }
+                ChunkState::Data => {
+                    let take = self.remaining.min((data.len() - i) as u64) as usize;
+                    if let Some(out) = out.as_mut() {
+                        out.extend_from_slice(&data[i..i + take]);
+                    }
+                    self.remaining -= take as u64;
+                    i += take;
+                    if self.remaining == 0 {
+                        self.state = ChunkState::DataCr;
+                    }
+                    continue;
+                }
+                ChunkState::DataCr => {
+                    if byte != b'\r' {
+                        return Err("Missing CRLF after chunk data");
+                    }
+                    self.state = ChunkState::DataLf;
+                }
+                ChunkState::DataLf => {
+                    if byte != b'\n' {
+                        return Err("Missing CRLF after chunk data");
+                    }
+                    self.size = 0;
+                    self.size_digits = 0;
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::TrailerStart => {
+                    if byte == b'\r' {
+                        self.state = ChunkState::FinalLf;
+                    } else {
+                        self.trailers.push(byte);
+                        self.state = ChunkState::TrailerLine;
+                    }
+                }
+                ChunkState::TrailerLine => {
+                    if self.trailers.len() >= MAX_TRAILER_BYTES {
+                        return Err("Trailers too large");
+                    }
+                    self.trailers.push(byte);
+                    if byte == b'\r' {
+                        self.state = ChunkState::TrailerLf;
+                    }
+                }
+                ChunkState::TrailerLf => {
+                    if byte != b'\n' {
+                        return Err("Invalid trailer line");
+                    }
+                    self.trailers.push(byte);
+                    self.state = ChunkState::TrailerStart;
+                }
+                ChunkState::FinalLf => {
+                    if byte != b'\n' {
+                        return Err("Invalid end of chunked body");
+                    }
+                    self.state = ChunkState::Done;
+                }
+                ChunkState::Done => {}
+            }
+            i += 1;
+        }
+
+        Ok((i, self.state == ChunkState::Done))
+    }
+}
+
+pub fn encode_chunk(data: &[u8], out: &mut Vec<u8>) {
+    // An empty chunk would read as the end of the body.
+    if data.is_empty() {
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+pub fn encode_last_chunk(trailers: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(b"0\r\n");
+    out.extend_from_slice(trailers);
+    out.extend_from_slice(b"\r\n");
+}
+
+// Re-encodes a complete chunked body as a single chunk plus trailers, so the
+// backend never sees the client's chunk sizes or extensions.
+pub fn normalize_chunked(body: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut decoder = ChunkedDecoder::new();
+    let mut decoded = Vec::new();
+    let (used, done) = decoder.advance(body, Some(&mut decoded))?;
+    if !done || used != body.len() {
+        return Err("Incomplete chunked body");
+    }
+
+    let mut out = Vec::new();
+    encode_chunk(&decoded, &mut out);
+    encode_last_chunk(&decoder.trailers, &mut out);
+    Ok(out)
+}
+
+// How the end of a message body is found.
+#[derive(Debug, Clone)]
+pub enum BodyFraming {
+    Empty,
+    ContentLength(u64),
+    Chunked(ChunkedDecoder),
+    CloseDelimited,
+}
+
+fn last_transfer_coding(headers: &header::HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .rfind(|coding| !coding.is_empty())
+}
+
+pub fn content_length(headers: &header::HeaderMap) -> Result<Option<u64>, &'static str> {
+    let mut length = None;
+    for value in headers.get_all(header::CONTENT_LENGTH) {
+        let value = value.to_str().map_err(|_| "Invalid Content-Length")?;
+        for part in value.split(',') {
+            // Digits only: u64's parser would also take a leading '+'.
+            let part = part.trim();
+            if part.is_empty() || !part.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err("Invalid Content-Length");
+            }
+            let parsed = part.parse::<u64>().map_err(|_| "Invalid Content-Length")?;
+            if length.is_some_and(|length| length != parsed) {
+                return Err("Conflicting Content-Length values");
+            }
+            length = Some(parsed);
+        }
+    }
+    Ok(length)
+}
+
+// Replaces an agreeing Content-Length list such as "5, 5" with the single
+// value, so the next hop cannot read it differently.
+pub fn collapse_content_length(headers: &mut header::HeaderMap) {
+    if let Ok(Some(length)) = content_length(headers) {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    }
+}
+
+// Makes chunked the final transfer coding, after any the backend applied
+// (gzip, say), which the client still has to undo.
+pub fn add_chunked_coding(headers: &mut header::HeaderMap) {
+    let codings: Vec<&str> = headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("chunked"))
+        .collect();
+    let value = codings.into_iter().chain(["chunked"]).collect::<Vec<_>>().join(", ");
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(header::TRANSFER_ENCODING, value);
+    }
+}
+
+impl BodyFraming {
+    pub fn for_request(req: &Request<()>) -> Result<BodyFraming, &'static str> {
+        // Framing that two parsers could read differently is how requests
+        // get smuggled past a proxy, so refuse it outright.
+        if req.headers().contains_key(header::TRANSFER_ENCODING) {
+            if req.headers().contains_key(header::CONTENT_LENGTH) {
+                return Err("Both Content-Length and Transfer-Encoding");
+            }
+            if req.version() == Version::HTTP_10 {
+                return Err("Transfer-Encoding in an HTTP/1.0 request");
+            }
+        }
+
+        if let Some(coding) = last_transfer_coding(req.headers()) {
+            // Without chunked as the final coding the length is unknowable.
+            return if coding == "chunked" {
+                Ok(BodyFraming::Chunked(ChunkedDecoder::new()))
+            } else {
+                Err("Unsupported Transfer-Encoding")
+            };
+        }
+
+        Ok(match content_length(req.headers())? {
+            Some(0) | None => BodyFraming::Empty,
+            Some(length) => BodyFraming::ContentLength(length),
+        })
+    }
+
+    pub fn for_response(res: &Response<()>, head_request: bool) -> Result<BodyFraming, &'static str> {
+        let status = res.status().as_u16();
+        if status == 101 {
+            return Ok(BodyFraming::CloseDelimited);
+        }
+        if head_request || (100..200).contains(&status) || status == 204 || status == 304 {
+            return Ok(BodyFraming::Empty);
+        }
+
+        if let Some(coding) = last_transfer_coding(res.headers()) {
+            return Ok(if coding == "chunked" {
+                BodyFraming::Chunked(ChunkedDecoder::new())
+            } else {
+                BodyFraming::CloseDelimited
+            });
+        }
+
+        Ok(match content_length(res.headers())? {
+            Some(length) => BodyFraming::ContentLength(length),
+            None => BodyFraming::CloseDelimited,
+        })
+    }
+
+    // Returns how many bytes of `data` belong to this body and whether the
+    // body is now complete.
+    pub fn advance(&mut self, data: &[u8]) -> Result<(usize, bool), &'static str> {
+        match self {
+            BodyFraming::Empty => Ok((0, true)),
+            BodyFraming::ContentLength(remaining) => {
+                let take = (*remaining).min(data.len() as u64);
+                *remaining -= take;
+                Ok((take as usize, *remaining == 0))
+            }
+            BodyFraming::Chunked(decoder) => decoder.advance(data, None),
+            BodyFraming::CloseDelimited => Ok((data.len(), false)),
+        }
+    }
+
+    // Like `advance`, but also appends the payload to `out`, with any
+    // chunked framing removed.
+    pub fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<(usize, bool), &'static str> {
+        match self {
+            BodyFraming::Chunked(decoder) => decoder.advance(data, Some(out)),
+            _ => {
+                let (used, done) = self.advance(data)?;
+                out.extend_from_slice(&data[..used]);
+                Ok((used, done))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked_coding(values: &[&'static str]) -> Vec<String> {
+        let mut headers = header::HeaderMap::new();
+        for value in values {
+            headers.append(header::TRANSFER_ENCODING, HeaderValue::from_static(value));
+        }
+        add_chunked_coding(&mut headers);
+        headers
+            .get_all(header::TRANSFER_ENCODING)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn chunked_goes_after_other_codings() {
+        assert_eq!(chunked_coding(&[]), ["chunked"]);
+        assert_eq!(chunked_coding(&["gzip"]), ["gzip, chunked"]);
+        assert_eq!(chunked_coding(&["gzip", "br"]), ["gzip, br, chunked"]);
+        assert_eq!(chunked_coding(&["Chunked, gzip"]), ["gzip, chunked"]);
+    }
+
+    fn decode_all(parts: &[&[u8]]) -> Result<(Vec<u8>, Vec<u8>), &'static str> {
+        let mut decoder = ChunkedDecoder::new();
+        let mut out = Vec::new();
+        let mut done = false;
+        for part in parts {
+            let (used, finished) = decoder.advance(part, Some(&mut out))?;
+            assert_eq!(used, part.len());
+            done = finished;
+        }
+        assert!(done, "body not finished");
+        Ok((out, decoder.trailers))
+    }
+
+    fn request(headers: &[(&str, &str)], version: Version) -> Request<()> {
+        let mut builder = Request::builder().uri("/").version(version);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn chunk_header_split_across_reads() {
+        let (body, _) = decode_all(&[b"1", b"0\r", b"\n0123456789abcdef\r\n0\r\n\r\n"]).unwrap();
+        assert_eq!(body, b"0123456789abcdef");
+        let (body, _) = decode_all(&[b"5\r\nhel", b"lo\r", b"\n", b"0", b"\r\n", b"\r", b"\n"]).unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn chunk_extensions_are_skipped() {
+        let (body, _) = decode_all(&[b"5;name=value\r\nhello\r\n3 ; x\r\nabc\r\n0;last\r\n\r\n"]).unwrap();
+        assert_eq!(body, b"helloabc");
+    }
+
+    #[test]
+    fn trailers_are_kept() {
+        let (body, trailers) = decode_all(&[b"2\r\nhi\r\n0\r\nX-Sum: 1\r\nX-Other: 2\r\n\r\n"]).unwrap();
+        assert_eq!(body, b"hi");
+        assert_eq!(trailers, b"X-Sum: 1\r\nX-Other: 2\r\n");
+    }
+
+    #[test]
+    fn bytes_after_the_body_are_not_consumed() {
+        let mut decoder = ChunkedDecoder::new();
+        assert_eq!(decoder.advance(b"0\r\n\r\nGET / HTTP/1.1", None), Ok((5, true)));
+    }
+
+    #[test]
+    fn bad_chunk_sizes_are_rejected() {
+        assert!(ChunkedDecoder::new().advance(b"g\r\n", None).is_err());
+        assert!(ChunkedDecoder::new().advance(b"\r\n", None).is_err());
+        assert!(ChunkedDecoder::new().advance(b";ext\r\n", None).is_err());
+        assert!(ChunkedDecoder::new().advance(b"-1\r\n", None).is_err());
+        assert!(ChunkedDecoder::new().advance(b"10000000000000000\r\n", None).is_err());
+        assert!(ChunkedDecoder::new().advance(b"2\r\nhiX", None).is_err());
+    }
+
+    #[test]
+    fn normalize_chunked_makes_one_chunk() {
+        assert_eq!(normalize_chunked(b"2;a=b\r\nhe\r\n3\r\nllo\r\n0\r\n\r\n").unwrap(), b"5\r\nhello\r\n0\r\n\r\n");
+        assert!(normalize_chunked(b"2\r\nhe\r\n").is_err());
+    }
+
+    #[test]
+    fn content_length_with_transfer_encoding_is_rejected() {
+        let req = request(&[("content-length", "5"), ("transfer-encoding", "chunked")], Version::HTTP_11);
+        assert!(BodyFraming::for_request(&req).is_err());
+    }
+
+    #[test]
+    fn transfer_encoding_in_http10_is_rejected() {
+        let req = request(&[("transfer-encoding", "chunked")], Version::HTTP_10);
+        assert!(BodyFraming::for_request(&req).is_err());
+    }
+
+    #[test]
+    fn chunked_must_be_the_last_coding() {
+        let req = request(&[("transfer-encoding", "chunked, gzip")], Version::HTTP_11);
+        assert!(BodyFraming::for_request(&req).is_err());
+        let req = request(&[("transfer-encoding", "gzip"), ("transfer-encoding", "chunked")], Version::HTTP_11);
+        assert!(matches!(BodyFraming::for_request(&req), Ok(BodyFraming::Chunked(_))));
+    }
+
+    #[test]
+    fn content_length_lists() {
+        let req = request(&[("content-length", "5, 5"), ("content-length", "5")], Version::HTTP_11);
+        assert!(matches!(BodyFraming::for_request(&req), Ok(BodyFraming::ContentLength(5))));
+        let req = request(&[("content-length", "5, 6")], Version::HTTP_11);
+        assert!(BodyFraming::for_request(&req).is_err());
+        let req = request(&[("content-length", "+5")], Version::HTTP_11);
+        assert!(BodyFraming::for_request(&req).is_err());
+
+        let mut headers = request(&[("content-length", "5, 5"), ("content-length", "5")], Version::HTTP_11).headers().clone();
+        collapse_content_length(&mut headers);
+        let values: Vec<_> = headers.get_all(header::CONTENT_LENGTH).iter().collect();
+        assert_eq!(values, [HeaderValue::from(5u64)]);
+    }
+}
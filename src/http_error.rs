@@ -0,0 +1,114 @@
+use http::header::{self, HeaderMap, HeaderValue};
+use http::StatusCode;
+
+use crate::config::Config;
+
+fn default_body(status: StatusCode) -> Vec<u8> {
+    format!(
+        "<html><body><h1>{} {}</h1></body></html>\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Error")
+    )
+    .into_bytes()
+}
+
+// A complete response with a fixed body, for answers that never reach a backend.
+pub fn direct_response(
+    status: u16,
+    headers: &HeaderMap,
+    body: &[u8],
+    keep_alive: bool,
+    head_request: bool
+) -> Vec<u8> {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Error")
+    )
+    .into_bytes();
+    // Framing is ours to decide, whatever the header rules said.
+    for (name, value) in headers {
+        if name != header::CONTENT_LENGTH && name != header::CONNECTION && name != header::TRANSFER_ENCODING {
+            response.extend_from_slice(name.as_str().as_bytes());
+            response.extend_from_slice(b": ");
+            response.extend_from_slice(value.as_bytes());
+            response.extend_from_slice(b"\r\n");
+        }
+    }
+    // 204 and 304 have neither a body nor a Content-Length (RFC 9110 8.6).
+    let bodyless = status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED;
+    if !bodyless {
+        response.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    }
+    response.extend_from_slice(format!(
+        "Connection: {}\r\n\r\n",
+        if keep_alive { "keep-alive" } else { "close" }
+    ).as_bytes());
+
+    if !head_request && !bodyless {
+        response.extend_from_slice(body);
+    }
+    response
+}
+
+pub fn error_response(status: u16, config: &Config) -> Vec<u8> {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+    let body = match config.error_pages.get(&status.as_u16()) {
+        Some(body) => body.clone(),
+        None => default_body(status),
+    };
+
+    let mut headers = HeaderMap::new();
+    if let Ok(content_type) = HeaderValue::from_str(&config.error_content_type) {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(config.retry_after_secs));
+    }
+    direct_response(status.as_u16(), &headers, &body, false, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, body: &[u8], head_request: bool) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        String::from_utf8(direct_response(status, &headers, body, true, head_request)).unwrap()
+    }
+
+    #[test]
+    fn responses_carry_their_length() {
+        assert_eq!(
+            response(200, b"ok", false),
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\nContent-Length: 2\r\nConnection: keep-alive\r\n\r\nok"
+        );
+        // HEAD gets the length of the body it does not get.
+        assert!(response(200, b"ok", true).ends_with("Content-Length: 2\r\nConnection: keep-alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn no_content_and_not_modified_have_no_length() {
+        for status in [204, 304] {
+            let response = response(status, b"", false);
+            assert!(!response.contains("Content-Length"), "{}", response);
+            assert!(response.ends_with("Connection: keep-alive\r\n\r\n"));
+        }
+    }
+
+    #[test]
+    fn header_rules_cannot_change_framing() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("99"));
+        headers.insert(header::TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert("x-served-by", HeaderValue::from_static("lb"));
+        let response = String::from_utf8(direct_response(200, &headers, b"ok", false, false)).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nx-served-by: lb\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+}
@@ -1,12 +1,20 @@
 mod worker;
 mod conn_db;
 mod least_conn_server;
+mod config;
+mod http_error;
+mod framing;
+mod forwarded;
+mod rewrite;
+mod routing;
+mod tls;
 
 use libc::*;
 use num_cpus;
 use std::net::Ipv4Addr;
 use worker::worker_loop;
 use conn_db::manage_connections;
+use config::{Config, ListenerMode, CONFIG_PATH};
 
 fn set_nonblocking(fd: libc::c_int) {
     unsafe {
@@ -21,9 +29,10 @@ fn set_nonblocking(fd: libc::c_int) {
     }
 }
 
-fn main() {
+// Binds a non-blocking listening socket shared by all workers (SO_REUSEPORT).
+fn bind_listener(bind_addr: &[u8; 6], udp: bool) -> i32 {
     unsafe {
-        let sock_fd = socket(AF_INET, SOCK_STREAM, 0);
+        let sock_fd = socket(AF_INET, if udp { SOCK_DGRAM } else { SOCK_STREAM }, 0);
         let yes = 1;
         setsockopt(
             sock_fd,
@@ -39,10 +48,10 @@ fn main() {
             &yes as *const _ as *const _,
             size_of::<i32>() as u32,
         );
-        let ip: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1).into();
+        let ip = Ipv4Addr::new(bind_addr[0], bind_addr[1], bind_addr[2], bind_addr[3]);
         let addr = sockaddr_in {
             sin_family: AF_INET as u8,
-            sin_port: htons(8080),
+            sin_port: htons(u16::from_be_bytes([bind_addr[4], bind_addr[5]])),
             sin_addr: in_addr {
                 s_addr: u32::from_ne_bytes(ip.octets()),
             },
@@ -61,17 +70,51 @@ fn main() {
 
         set_nonblocking(sock_fd);
 
-        if listen(sock_fd, 10) < 0 {
+        if !udp && listen(sock_fd, 10) < 0 {
             panic!("listen failed");
         }
+
+        sock_fd
+    }
+}
+
+fn main() {
+    let config = match Config::load(CONFIG_PATH) {
+        Ok(config) => config,
+        Err(e) => panic!("Failed to load config: {}", e),
+    };
+
+    unsafe {
+        // A client or backend that hangs up mid-write must not kill the worker.
+        signal(SIGPIPE, SIG_IGN);
+
+        let sock_fd = bind_listener(&[127, 0, 0, 1, 0x1f, 0x90], false);
         println!("Listening on 127.0.0.1:8080");
 
+        let listener_fds: Vec<i32> = config
+            .listeners
+            .iter()
+            .map(|listener| {
+                let fd = bind_listener(&listener.bind, listener.mode == ListenerMode::Udp);
+                println!(
+                    "Listening on {}.{}.{}.{}:{} ({})",
+                    listener.bind[0],
+                    listener.bind[1],
+                    listener.bind[2],
+                    listener.bind[3],
+                    u16::from_be_bytes([listener.bind[4], listener.bind[5]]),
+                    listener.name
+                );
+                fd
+            })
+            .collect();
+
         let cpu_count: usize = num_cpus::get();
         let mut workers: Vec<i32> = Vec::new();
 
         let conn_db_pid = fork();
         if conn_db_pid == 0 {
-            manage_connections(cpu_count-1);
+            manage_connections(cpu_count-1, &config);
             std::process::exit(0);
         } else if conn_db_pid > 0 {
             
@@ -79,10 +122,19 @@ fn main() {
             panic!("Fork Failed...");
         }
 
-        for _ in 0..(cpu_count - 2) {
+        for worker in 0..(cpu_count - 2) {
             let pid = fork();
             if pid == 0 {
-                worker_loop(sock_fd);
+                // UDP flows live in one worker's memory, so only the first
+                // worker reads UDP listeners; a flow then always keeps its backend.
+                let worker_fds: Vec<i32> = listener_fds
+                    .iter()
+                    .zip(&config.listeners)
+                    .map(|(fd, listener)| {
+                        if listener.mode == ListenerMode::Udp && worker != 0 { -1 } else { *fd }
+                    })
+                    .collect();
+                worker_loop(sock_fd, &worker_fds, &config);
                 std::process::exit(0);
             } else if pid > 0 {
                 workers.push(pid);
@@ -0,0 +1,285 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+// One header rewrite from the config:
+//   add <name> <value>, set <name> <value>, remove <name>, rename <from> <to>
+#[derive(Debug, Clone)]
+pub enum HeaderRule {
+    Add(HeaderName, String),
+    Set(HeaderName, String),
+    Remove(HeaderName),
+    Rename(HeaderName, HeaderName),
+}
+
+fn header_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name {}", name))
+}
+
+impl HeaderRule {
+    pub fn parse(rule: &str) -> Result<HeaderRule, String> {
+        let mut parts = rule.splitn(3, char::is_whitespace);
+        let action = parts.next().unwrap_or_default();
+        let name = header_name(parts.next().unwrap_or_default())?;
+        let arg = parts.next().map(|arg| arg.trim()).unwrap_or_default();
+
+        match (action, arg.is_empty()) {
+            ("add", false) => Ok(HeaderRule::Add(name, arg.to_string())),
+            ("set", false) => Ok(HeaderRule::Set(name, arg.to_string())),
+            ("remove", true) => Ok(HeaderRule::Remove(name)),
+            ("rename", false) => Ok(HeaderRule::Rename(name, header_name(arg)?)),
+            _ => Err(format!("invalid header rule: {}", rule)),
+        }
+    }
+}
+
+// Values that rule templates can refer to as $name or ${name}.
+pub struct Vars<'a> {
+    pub client_ip: &'a str,
+    pub backend_addr: &'a str,
+    pub request_id: &'a str,
+    // The host the client asked for, without port, and the request target.
+    pub host: &'a str,
+    pub request_uri: &'a str,
+    // "https" for requests that came in over TLS, else "http".
+    pub scheme: &'a str,
+}
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Unique across the workers of one balancer: pid, start time and a counter.
+pub fn new_request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0);
+    format!(
+        "{:08x}{:016x}{:08x}",
+        std::process::id(),
+        nanos,
+        REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed) as u32
+    )
+}
+
+// Formats seconds since the epoch as an ISO 8601 UTC timestamp.
+fn iso8601(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn lookup(name: &str, vars: &Vars) -> Option<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    match name {
+        "client_ip" => Some(vars.client_ip.to_string()),
+        "backend_addr" => Some(vars.backend_addr.to_string()),
+        "request_id" => Some(vars.request_id.to_string()),
+        "host" => Some(vars.host.to_string()),
+        "request_uri" => Some(vars.request_uri.to_string()),
+        "scheme" => Some(vars.scheme.to_string()),
+        "time_unix" => Some(now.as_secs().to_string()),
+        "time_msec" => Some(now.as_millis().to_string()),
+        "time_iso8601" => Some(iso8601(now.as_secs())),
+        _ => None,
+    }
+}
+
+// Replaces $name and ${name} with their values; `$$` is a literal `$` and
+// unknown names are left as written.
+pub fn expand(template: &str, vars: &Vars) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+
+        let (name, len) = match rest.strip_prefix('{').and_then(|braced| braced.split_once('}')) {
+            Some((name, _)) => (name, name.len() + 2),
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        match lookup(name, vars) {
+            Some(value) => out.push_str(&value),
+            None => {
+                out.push('$');
+                out.push_str(&rest[..len]);
+            }
+        }
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+pub fn apply_rules(headers: &mut HeaderMap, rules: &[HeaderRule], vars: &Vars) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Add(name, template) => {
+                if let Ok(value) = HeaderValue::from_str(&expand(template, vars)) {
+                    headers.append(name.clone(), value);
+                }
+            }
+            HeaderRule::Set(name, template) => {
+                if let Ok(value) = HeaderValue::from_str(&expand(template, vars)) {
+                    headers.insert(name.clone(), value);
+                }
+            }
+            HeaderRule::Remove(name) => {
+                headers.remove(name);
+            }
+            HeaderRule::Rename(from, to) => {
+                let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+                headers.remove(from);
+                for value in values {
+                    headers.append(to.clone(), value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars<'static> {
+        Vars {
+            client_ip: "192.0.2.1",
+            backend_addr: "10.0.0.5:3000",
+            request_id: "abc123",
+            host: "example.com",
+            request_uri: "/a?b=1",
+            scheme: "https",
+        }
+    }
+
+    #[test]
+    fn rules_parse() {
+        assert!(matches!(HeaderRule::parse("add X-A one two"), Ok(HeaderRule::Add(name, value)) if name == "x-a" && value == "one two"));
+        assert!(matches!(HeaderRule::parse("set X-A $host"), Ok(HeaderRule::Set(_, value)) if value == "$host"));
+        assert!(matches!(HeaderRule::parse("remove Server"), Ok(HeaderRule::Remove(name)) if name == "server"));
+        assert!(matches!(HeaderRule::parse("rename X-Old X-New"), Ok(HeaderRule::Rename(from, to)) if from == "x-old" && to == "x-new"));
+        for rule in ["add X-A", "remove Server now", "rename X-Old", "rename X-Old bad\u{7f}", "replace X-A b", "set", ""] {
+            assert!(HeaderRule::parse(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn expand_substitutes_variables() {
+        let vars = vars();
+        assert_eq!(expand("$client_ip via $backend_addr", &vars), "192.0.2.1 via 10.0.0.5:3000");
+        assert_eq!(expand("id-${request_id}-x", &vars), "id-abc123-x");
+        assert_eq!(expand("$scheme://$host$request_uri", &vars), "https://example.com/a?b=1");
+        assert_eq!(expand("$$host costs $$5", &vars), "$host costs $5");
+        assert!(expand("$time_unix", &vars).parse::<u64>().is_ok());
+        assert!(expand("$time_iso8601", &vars).ends_with('Z'));
+    }
+
+    #[test]
+    fn expand_keeps_unknown_variables() {
+        let vars = vars();
+        assert_eq!(expand("$nope and ${nope}", &vars), "$nope and ${nope}");
+        assert_eq!(expand("${host", &vars), "${host");
+        assert_eq!(expand("cost: 5$", &vars), "cost: 5$");
+        assert_eq!(expand("$host-name", &vars), "example.com-name");
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-tag", HeaderValue::from_static("one"));
+        headers.append("x-tag", HeaderValue::from_static("two"));
+        headers.append("server", HeaderValue::from_static("backend"));
+        let rules: Vec<HeaderRule> = [
+            "add X-Tag three",
+            "rename X-Tag X-Label",
+            "set X-Label $host",
+            "add X-Label second",
+            "remove Server",
+            "set Server lb",
+            "remove X-Missing",
+            "set X-Bad bad\u{1}value",
+        ]
+        .iter()
+        .map(|rule| HeaderRule::parse(rule).unwrap())
+        .collect();
+        apply_rules(&mut headers, &rules, &vars());
+        assert!(values(&headers, "x-tag").is_empty());
+        assert_eq!(values(&headers, "x-label"), ["example.com", "second"]);
+        assert_eq!(values(&headers, "server"), ["lb"]);
+        // A value that is not a valid header value is dropped.
+        assert!(!headers.contains_key("x-bad"));
+    }
+
+    #[test]
+    fn rename_keeps_every_value() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-old", HeaderValue::from_static("a"));
+        headers.append("x-old", HeaderValue::from_static("b"));
+        headers.append("x-new", HeaderValue::from_static("z"));
+        apply_rules(&mut headers, &[HeaderRule::parse("rename X-Old X-New").unwrap()], &vars());
+        assert_eq!(values(&headers, "x-new"), ["z", "a", "b"]);
+    }
+
+    #[test]
+    fn iso8601_dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        // 2000 is a leap year (divisible by 400), 2100 is not.
+        assert_eq!(iso8601(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(951868799), "2000-02-29T23:59:59Z");
+        assert_eq!(iso8601(951868800), "2000-03-01T00:00:00Z");
+        assert_eq!(iso8601(1709251199), "2024-02-29T23:59:59Z");
+        assert_eq!(iso8601(1677628799), "2023-02-28T23:59:59Z");
+        assert_eq!(iso8601(1677628800), "2023-03-01T00:00:00Z");
+        assert_eq!(iso8601(4107542399), "2100-02-28T23:59:59Z");
+        // End of the year.
+        assert_eq!(iso8601(1704067199), "2023-12-31T23:59:59Z");
+        assert_eq!(iso8601(1704067200), "2024-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn request_ids_are_unique() {
+        let first = new_request_id();
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, new_request_id());
+    }
+}
@@ -0,0 +1,622 @@
+use std::sync::Arc;
+
+use http::{header, header::HeaderName, Method, Request, Uri};
+use regex::Regex;
+use rustls::RootCertStore;
+
+use crate::rewrite::HeaderRule;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    // *.example.com, stored as ".example.com"; matches any subdomain.
+    Wildcard(String),
+    // *, the catch-all virtual host.
+    Any,
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> HostPattern {
+        let pattern = normalize_host(pattern);
+        match pattern.strip_prefix('*') {
+            Some("") => HostPattern::Any,
+            Some(suffix) if suffix.starts_with('.') => HostPattern::Wildcard(suffix.to_string()),
+            _ => HostPattern::Exact(pattern),
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => host == exact,
+            HostPattern::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+            HostPattern::Any => true,
+        }
+    }
+}
+
+// Lowercases and drops the port and any trailing dot.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    let host = if host.starts_with('[') {
+        // [v6]:port
+        match host.find(']') {
+            Some(end) => host[..=end].to_string(),
+            None => host,
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name.to_string(),
+            _ => host,
+        }
+    };
+    host.trim_end_matches('.').to_string()
+}
+
+// The host the client asked for: the authority of an absolute-form target,
+// else the Host header.
+pub fn request_host<T>(req: &Request<T>) -> Option<String> {
+    if let Some(authority) = req.uri().authority() {
+        return Some(normalize_host(authority.host()));
+    }
+    req.headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(normalize_host)
+        .filter(|host| !host.is_empty())
+}
+
+#[derive(Debug, Clone)]
+pub enum MatchType {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+    Present,
+}
+
+impl MatchType {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            MatchType::Exact(exact) => value == exact,
+            MatchType::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            MatchType::Regex(regex) => regex.is_match(value),
+            MatchType::Present => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Attribute {
+    Header(HeaderName),
+    Cookie(String),
+    Query(String),
+}
+
+// A condition on a header, cookie or query parameter, written in the config
+// as `<name> <exact|prefix|regex> <value>` or `<name> present`.
+#[derive(Debug, Clone)]
+pub struct AttributeMatch {
+    attribute: Attribute,
+    match_type: MatchType,
+}
+
+// Decodes %XX escapes and `+` as used in query strings.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let escaped = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl AttributeMatch {
+    pub fn parse(kind: &str, value: &str) -> Result<AttributeMatch, String> {
+        let mut parts = value.splitn(3, char::is_whitespace);
+        let name = parts.next().unwrap_or_default();
+        let match_type = parts.next().unwrap_or_default();
+        let arg = parts.next().map(|arg| arg.trim()).unwrap_or_default();
+
+        let attribute = match kind {
+            "header" => Attribute::Header(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name {}", name))?,
+            ),
+            "cookie" => Attribute::Cookie(name.to_string()),
+            _ => Attribute::Query(name.to_string()),
+        };
+        let match_type = match (match_type, arg.is_empty()) {
+            ("exact", false) => MatchType::Exact(arg.to_string()),
+            ("prefix", false) => MatchType::Prefix(arg.to_string()),
+            ("regex", false) => MatchType::Regex(Regex::new(arg).map_err(|e| format!("invalid regex {}: {}", arg, e))?),
+            ("present", true) => MatchType::Present,
+            _ => return Err(format!("invalid {} condition: {}", kind, value)),
+        };
+        if name.is_empty() {
+            return Err(format!("invalid {} condition: {}", kind, value));
+        }
+        Ok(AttributeMatch { attribute, match_type })
+    }
+
+    fn matches<T>(&self, req: &Request<T>) -> bool {
+        let values: Vec<String> = match &self.attribute {
+            Attribute::Header(name) => req
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+                .collect(),
+            Attribute::Cookie(name) => req
+                .headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .filter(|(key, _)| key.trim() == name)
+                .map(|(_, value)| value.trim().trim_matches('"').to_string())
+                .collect(),
+            Attribute::Query(name) => req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+                .filter(|(key, _)| percent_decode(key) == *name)
+                .map(|(_, value)| percent_decode(value))
+                .collect(),
+        };
+        values.iter().any(|value| self.match_type.matches(value))
+    }
+}
+
+// What to do with a request once a route matches it.
+#[derive(Debug, Clone)]
+pub enum RouteAction {
+    // Send it to the route's pool.
+    Proxy,
+    // Answer with a redirect; the location may use rewrite variables.
+    Redirect(u16, String),
+    // Answer from the config without touching a backend.
+    Respond {
+        status: u16,
+        content_type: String,
+        body: Vec<u8>,
+    },
+}
+
+// One entry of the routing table. Every condition that is set must match;
+// the first matching route picks the pool.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub name: String,
+    pub hosts: Vec<HostPattern>,
+    // "http" or "https": whether the request came in over TLS.
+    pub scheme: Option<String>,
+    pub methods: Vec<Method>,
+    pub path_prefix: Option<String>,
+    pub path_regex: Option<Regex>,
+    pub attributes: Vec<AttributeMatch>,
+    pub pool: String,
+    pub action: RouteAction,
+    pub strip_prefix: bool,
+    pub rewrite: Option<(Regex, String)>,
+    pub request_headers: Vec<HeaderRule>,
+    pub response_headers: Vec<HeaderRule>,
+    // Only clients whose certificate chains to one of these get through;
+    // others are answered 403.
+    pub client_ca: Option<Arc<RootCertStore>>,
+}
+
+impl Route {
+    pub fn new(name: &str) -> Route {
+        Route {
+            name: name.to_string(),
+            hosts: Vec::new(),
+            scheme: None,
+            methods: Vec::new(),
+            path_prefix: None,
+            path_regex: None,
+            attributes: Vec::new(),
+            pool: String::new(),
+            action: RouteAction::Proxy,
+            strip_prefix: false,
+            rewrite: None,
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            client_ca: None,
+        }
+    }
+
+    pub fn matches<T>(&self, req: &Request<T>, host: Option<&str>, scheme: &str) -> bool {
+        let path = req.uri().path();
+        if !self.hosts.is_empty() && !host.is_some_and(|host| self.hosts.iter().any(|pattern| pattern.matches(host))) {
+            return false;
+        }
+        if self.scheme.as_ref().is_some_and(|wanted| wanted != scheme) {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return false;
+        }
+        if self.path_prefix.as_ref().is_some_and(|prefix| !prefix_matches(prefix, path)) {
+            return false;
+        }
+        if self.path_regex.as_ref().is_some_and(|regex| !regex.is_match(path)) {
+            return false;
+        }
+        self.attributes.iter().all(|attribute| attribute.matches(req))
+    }
+
+    // The origin-form target to send to the backend, if this route changes it.
+    pub fn rewrite_target(&self, uri: &Uri) -> Option<Uri> {
+        if !self.strip_prefix && self.rewrite.is_none() {
+            return None;
+        }
+
+        let mut path = uri.path().to_string();
+        if let (true, Some(prefix)) = (self.strip_prefix, &self.path_prefix) {
+            path = path[prefix.len().min(path.len())..].to_string();
+            if !path.starts_with('/') {
+                path.insert(0, '/');
+            }
+        }
+        if let Some((regex, replacement)) = &self.rewrite {
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+
+        let target = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        target.parse().ok()
+    }
+}
+
+// A path prefix covers whole segments: /api matches /api and /api/users but
+// not /apiv2, while /api/ only matches below /api/.
+fn prefix_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+// The request target with its path in normal form, if that differs: %XX
+// escapes of unreserved characters decoded, repeated slashes merged and dot
+// segments resolved (none climbing above the root), so a route sees the
+// same path however the client spelled it.
+pub fn normalize_target(uri: &Uri) -> Option<Uri> {
+    let path = uri.path();
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+            .filter(|c| c.is_ascii_alphanumeric() || b"-._~".contains(c));
+        match escaped {
+            Some(c) if bytes[i] == b'%' => {
+                decoded.push(c as char);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i] as char);
+                i += 1;
+            }
+        }
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normal = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && matches!(decoded.rsplit('/').next(), Some("" | "." | "..")) {
+        normal.push('/');
+    }
+    if normal == path {
+        return None;
+    }
+
+    let target = match uri.query() {
+        Some(query) => format!("{}?{}", normal, query),
+        None => normal,
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(target.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+pub fn route_request<'a, T>(routes: &'a [Route], req: &Request<T>, host: Option<&str>, scheme: &str) -> Option<(usize, &'a Route)> {
+    routes.iter().enumerate().find(|(_, route)| route.matches(req, host, scheme))
+}
+
+// Whether some route serves this host. With no host conditions configured
+// every host is known.
+pub fn is_known_host(routes: &[Route], host: Option<&str>) -> bool {
+    let mut patterns = routes.iter().flat_map(|route| route.hosts.iter()).peekable();
+    if patterns.peek().is_none() {
+        return true;
+    }
+    match host {
+        Some(host) => patterns.any(|pattern| pattern.matches(host)),
+        None => patterns.any(|pattern| *pattern == HostPattern::Any),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(target: &str) -> String {
+        let uri: Uri = target.parse().unwrap();
+        normalize_target(&uri).unwrap_or(uri).to_string()
+    }
+
+    #[test]
+    fn normal_paths_are_left_alone() {
+        for target in ["/", "/internal", "/internal/", "/a/b?x=/../y", "/a%2Fb", "/%E2%82%AC", "*"] {
+            assert_eq!(normalize(target), target);
+        }
+    }
+
+    #[test]
+    fn unreserved_escapes_are_decoded() {
+        assert_eq!(normalize("/%69nternal"), "/internal");
+        assert_eq!(normalize("/%2e%2E/internal"), "/internal");
+        assert_eq!(normalize("/a%2db%7E"), "/a-b~");
+        // Reserved characters and bad escapes stay as they are.
+        assert_eq!(normalize("/a%2f%zz%4"), "/a%2f%zz%4");
+    }
+
+    #[test]
+    fn slashes_are_merged_and_dot_segments_resolved() {
+        assert_eq!(normalize("//internal"), "/internal");
+        assert_eq!(normalize("/./internal"), "/internal");
+        assert_eq!(normalize("/public/../internal/./x"), "/internal/x");
+        assert_eq!(normalize("/../../internal"), "/internal");
+        assert_eq!(normalize("/a/b/.."), "/a/");
+        assert_eq!(normalize("/a//b/.?q=1"), "/a/b/?q=1");
+        assert_eq!(normalize("/.."), "/");
+    }
+
+    #[test]
+    fn absolute_form_keeps_its_authority() {
+        assert_eq!(normalize("http://example.com//internal"), "http://example.com/internal");
+    }
+
+    #[test]
+    fn host_patterns() {
+        let exact = HostPattern::parse("Example.com");
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+        let wildcard = HostPattern::parse("*.example.com");
+        assert_eq!(wildcard, HostPattern::Wildcard(String::from(".example.com")));
+        assert!(wildcard.matches("www.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+        assert_eq!(HostPattern::parse("*"), HostPattern::Any);
+    }
+
+    #[test]
+    fn hosts_lose_case_port_and_trailing_dot() {
+        assert_eq!(normalize_host("Example.COM:8080"), "example.com");
+        assert_eq!(normalize_host("example.com."), "example.com");
+        assert_eq!(normalize_host("[::1]:443"), "[::1]");
+        assert_eq!(normalize_host("[::1]"), "[::1]");
+        // Not a port, so it stays.
+        assert_eq!(normalize_host("example.com:http"), "example.com:http");
+
+        let req = Request::builder().uri("/").header("host", "WWW.Example.com:8080").body(()).unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("www.example.com"));
+        let req = Request::builder().uri("http://api.example.com/x").header("host", "other").body(()).unwrap();
+        assert_eq!(request_host(&req).as_deref(), Some("api.example.com"));
+        let req = Request::builder().uri("/").header("host", "").body(()).unwrap();
+        assert_eq!(request_host(&req), None);
+    }
+
+    fn host_route(hosts: &str) -> Route {
+        let mut route = Route::new("test");
+        route.hosts = hosts.split(',').map(HostPattern::parse).collect();
+        route
+    }
+
+    #[test]
+    fn unknown_hosts() {
+        // Without host conditions every host is known.
+        assert!(is_known_host(&[Route::new("any")], Some("anything.test")));
+        assert!(is_known_host(&[], None));
+
+        let routes = [host_route("example.com, *.example.com"), Route::new("rest")];
+        assert!(is_known_host(&routes, Some("example.com")));
+        assert!(is_known_host(&routes, Some("www.example.com")));
+        assert!(!is_known_host(&routes, Some("example.org")));
+        assert!(!is_known_host(&routes, None));
+
+        let routes = [host_route("example.com"), host_route("*")];
+        assert!(is_known_host(&routes, Some("example.org")));
+        assert!(is_known_host(&routes, None));
+    }
+
+    fn attribute_matches(kind: &str, condition: &str, req: &Request<()>) -> bool {
+        AttributeMatch::parse(kind, condition).unwrap().matches(req)
+    }
+
+    fn with_cookies(cookies: &[&str]) -> Request<()> {
+        let mut builder = Request::builder().uri("/");
+        for cookie in cookies {
+            builder = builder.header("cookie", *cookie);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn attribute_conditions_parse() {
+        assert!(AttributeMatch::parse("header", "X-Tenant exact acme").is_ok());
+        assert!(AttributeMatch::parse("cookie", "session present").is_ok());
+        assert!(AttributeMatch::parse("query", "v regex ^[0-9]+$").is_ok());
+        assert!(AttributeMatch::parse("header", "X-Tenant exact").is_err());
+        assert!(AttributeMatch::parse("header", "X-Tenant present acme").is_err());
+        assert!(AttributeMatch::parse("header", "X-Tenant sounds_like acme").is_err());
+        assert!(AttributeMatch::parse("header", "bad\u{7f}name present").is_err());
+        assert!(AttributeMatch::parse("query", "v regex (").is_err());
+    }
+
+    #[test]
+    fn header_conditions() {
+        let req = Request::builder()
+            .uri("/")
+            .header("x-tenant", "other")
+            .header("x-tenant", "acme-eu")
+            .body(())
+            .unwrap();
+        assert!(attribute_matches("header", "X-Tenant prefix acme", &req));
+        assert!(!attribute_matches("header", "X-Tenant exact acme", &req));
+        assert!(!attribute_matches("header", "X-Missing present", &req));
+    }
+
+    #[test]
+    fn cookie_conditions() {
+        let req = with_cookies(&["theme=dark; ab_group=beta-2", "ab_group=control"]);
+        // Any of the repeated cookies may match.
+        assert!(attribute_matches("cookie", "ab_group prefix beta", &req));
+        assert!(attribute_matches("cookie", "ab_group exact control", &req));
+        assert!(!attribute_matches("cookie", "theme exact light", &req));
+
+        let req = with_cookies(&["token=a=b=c; quoted=\"x y\"; bare; =nameless"]);
+        assert!(attribute_matches("cookie", "token exact a=b=c", &req));
+        assert!(attribute_matches("cookie", "quoted exact x y", &req));
+        assert!(!attribute_matches("cookie", "bare present", &req));
+        assert!(!attribute_matches("cookie", "session present", &with_cookies(&[])));
+    }
+
+    #[test]
+    fn query_conditions() {
+        let req = request(Method::GET, "/?preview&lang=en&lang=fr&q=a%20b+c&eq=x=y&%6Bey=1");
+        assert!(attribute_matches("query", "preview present", &req));
+        // A parameter without `=` has an empty value.
+        assert!(attribute_matches("query", "preview regex ^$", &req));
+        assert!(attribute_matches("query", "lang exact fr", &req));
+        assert!(attribute_matches("query", "q exact a b c", &req));
+        assert!(attribute_matches("query", "eq exact x=y", &req));
+        assert!(attribute_matches("query", "key present", &req));
+        assert!(!attribute_matches("query", "preview present", &request(Method::GET, "/")));
+        assert!(!attribute_matches("query", "preview present", &request(Method::GET, "/?")));
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%%41"), "%A");
+        assert_eq!(percent_decode("%C3%A9+%FF"), "\u{e9} \u{fffd}");
+    }
+
+    fn request(method: Method, target: &str) -> Request<()> {
+        Request::builder().method(method).uri(target).body(()).unwrap()
+    }
+
+    fn prefix_route(prefix: &str, strip_prefix: bool) -> Route {
+        let mut route = Route::new("test");
+        route.path_prefix = Some(prefix.to_string());
+        route.strip_prefix = strip_prefix;
+        route
+    }
+
+    fn matches(route: &Route, target: &str) -> bool {
+        route.matches(&request(Method::GET, target), None, "http")
+    }
+
+    #[test]
+    fn path_prefix_matches_whole_segments() {
+        let route = prefix_route("/api", false);
+        assert!(matches(&route, "/api"));
+        assert!(matches(&route, "/api/"));
+        assert!(matches(&route, "/api/users?id=1"));
+        assert!(!matches(&route, "/apiv2/users"));
+        assert!(!matches(&route, "/ap"));
+        assert!(!matches(&route, "/"));
+
+        let route = prefix_route("/api/", false);
+        assert!(matches(&route, "/api/users"));
+        assert!(!matches(&route, "/api"));
+        assert!(matches(&prefix_route("/", false), "/anything"));
+    }
+
+    #[test]
+    fn route_conditions_must_all_match() {
+        let mut route = prefix_route("/api", false);
+        route.methods = vec![Method::GET, Method::HEAD];
+        route.hosts = vec![HostPattern::parse("example.com")];
+        route.path_regex = Some(Regex::new("/[0-9]+$").unwrap());
+        assert!(route.matches(&request(Method::GET, "/api/users/7"), Some("example.com"), "http"));
+        assert!(!route.matches(&request(Method::POST, "/api/users/7"), Some("example.com"), "http"));
+        assert!(!route.matches(&request(Method::GET, "/api/users/7"), Some("other.com"), "http"));
+        assert!(!route.matches(&request(Method::GET, "/api/users/7"), None, "http"));
+        assert!(!route.matches(&request(Method::GET, "/api/users/me"), Some("example.com"), "http"));
+    }
+
+    #[test]
+    fn scheme_condition() {
+        let mut route = Route::new("to_https");
+        route.scheme = Some(String::from("http"));
+        let req = request(Method::GET, "/login");
+        assert!(route.matches(&req, None, "http"));
+        assert!(!route.matches(&req, None, "https"));
+        let routes = [route, Route::new("rest")];
+        assert_eq!(route_request(&routes, &req, None, "https").map(|(index, _)| index), Some(1));
+    }
+
+    fn rewrite(route: &Route, target: &str) -> Option<String> {
+        route.rewrite_target(&target.parse().unwrap()).map(|uri| uri.to_string())
+    }
+
+    #[test]
+    fn strip_prefix_keeps_the_rest_of_the_path() {
+        let route = prefix_route("/api", true);
+        assert_eq!(rewrite(&route, "/api/users?id=1").as_deref(), Some("/users?id=1"));
+        assert_eq!(rewrite(&route, "/api").as_deref(), Some("/"));
+        assert_eq!(rewrite(&prefix_route("/api/", true), "/api/users").as_deref(), Some("/users"));
+        assert_eq!(rewrite(&prefix_route("/api", false), "/api/users"), None);
+    }
+
+    #[test]
+    fn rewrite_runs_after_strip_prefix() {
+        let mut route = prefix_route("/legacy", true);
+        route.rewrite = Some((Regex::new("^/v1/(.*)").unwrap(), String::from("/v2/$1")));
+        assert_eq!(rewrite(&route, "/legacy/v1/items?x=1").as_deref(), Some("/v2/items?x=1"));
+        assert_eq!(rewrite(&route, "/legacy/other").as_deref(), Some("/other"));
+        // Absolute-form targets come out in origin form.
+        assert_eq!(rewrite(&route, "http://example.com/legacy/v1/a").as_deref(), Some("/v2/a"));
+    }
+}
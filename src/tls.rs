@@ -0,0 +1,704 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::{ClientCert, ListenerConfig, PoolConfig};
+
+const PLAINTEXT_CHUNK: usize = 16384;
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate in {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid private key in {}: {}", path.display(), e))?
+        .ok_or(format!("no private key in {}", path.display()))
+}
+
+pub fn load_roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("invalid CA certificate in {}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    (modified(cert_path), modified(key_path))
+}
+
+// A certificate and key as last loaded from disk.
+#[derive(Debug)]
+struct CertPair {
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    // DNS names from the certificate, lowercased; wildcards as "*.example.com".
+    names: Vec<String>,
+    key: Arc<CertifiedKey>,
+}
+
+fn load_pair(cert_path: &Path, key_path: &Path) -> Result<CertPair, String> {
+    let modified = modified(cert_path, key_path);
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let names = webpki::EndEntityCert::try_from(&certs[0])
+        .map(|cert| cert.valid_dns_names().map(|name| name.to_ascii_lowercase()).collect())
+        .unwrap_or_default();
+    let key = CertifiedKey::from_der(certs, key, &ring::default_provider())
+        .map_err(|e| format!("{} does not go with {}: {}", key_path.display(), cert_path.display(), e))?;
+    Ok(CertPair { modified, names, key: Arc::new(key) })
+}
+
+#[derive(Debug, Default)]
+struct CertStore {
+    default: Option<CertPair>,
+    // Pairs from the certificate directory, by file name without extension.
+    pairs: HashMap<String, CertPair>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    checked: Option<Instant>,
+}
+
+// Picks a listener's certificate by SNI: an exact name from a certificate in
+// tls_cert_dir, then a wildcard one, then tls_cert. The files are checked
+// again at most every reload_ms and changed pairs are reloaded in place, so
+// new handshakes get them without restarting the workers.
+#[derive(Debug)]
+pub struct SniResolver {
+    default_paths: Option<(PathBuf, PathBuf)>,
+    cert_dir: Option<PathBuf>,
+    reload_ms: u64,
+    store: Mutex<CertStore>,
+}
+
+impl SniResolver {
+    pub fn new(default_paths: Option<(PathBuf, PathBuf)>, cert_dir: Option<PathBuf>, reload_ms: u64) -> Result<SniResolver, String> {
+        let resolver = SniResolver { default_paths, cert_dir, reload_ms, store: Mutex::new(CertStore::default()) };
+        {
+            let mut store = resolver.store.lock().unwrap();
+            resolver.reload(&mut store, true)?;
+            if store.default.is_none() && store.pairs.is_empty() {
+                return Err(String::from("no certificates to serve"));
+            }
+        }
+        Ok(resolver)
+    }
+
+    // Loads every pair whose files changed since they were last loaded. At
+    // startup a bad pair is an error; later it is reported and the pair
+    // loaded before stays in use.
+    fn reload(&self, store: &mut CertStore, strict: bool) -> Result<(), String> {
+        store.checked = Some(Instant::now());
+
+        if let Some((cert_path, key_path)) = &self.default_paths {
+            let changed = store
+                .default
+                .as_ref()
+                .is_none_or(|pair| pair.modified != modified(cert_path, key_path));
+            if changed {
+                match load_pair(cert_path, key_path) {
+                    Ok(pair) => store.default = Some(pair),
+                    Err(e) if strict => return Err(e),
+                    Err(e) => eprintln!("Keeping previous certificate: {}", e),
+                }
+            }
+        }
+
+        if let Some(dir) = &self.cert_dir {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) if strict => return Err(format!("failed to read {}: {}", dir.display(), e)),
+                Err(e) => {
+                    eprintln!("Keeping previous certificates: failed to read {}: {}", dir.display(), e);
+                    return Ok(());
+                }
+            };
+            // <name>.crt goes with <name>.key.
+            let mut seen: HashSet<String> = HashSet::new();
+            for cert_path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                if cert_path.extension().is_none_or(|ext| ext != "crt") {
+                    continue;
+                }
+                let stem = match cert_path.file_stem().and_then(|stem| stem.to_str()) {
+                    Some(stem) => stem.to_string(),
+                    None => continue,
+                };
+                let key_path = cert_path.with_extension("key");
+                seen.insert(stem.clone());
+                if store.pairs.get(&stem).is_some_and(|pair| pair.modified == modified(&cert_path, &key_path)) {
+                    continue;
+                }
+                match load_pair(&cert_path, &key_path) {
+                    Ok(pair) => {
+                        store.pairs.insert(stem, pair);
+                    }
+                    Err(e) if strict => return Err(e),
+                    Err(e) => eprintln!("Keeping previous certificate: {}", e),
+                }
+            }
+            store.pairs.retain(|stem, _| seen.contains(stem));
+        }
+
+        // When two files claim a name, the first by file name wins.
+        let mut stems: Vec<&String> = store.pairs.keys().collect();
+        stems.sort();
+        let mut by_name: HashMap<String, Arc<CertifiedKey>> = HashMap::new();
+        for stem in stems {
+            let pair = &store.pairs[stem];
+            for name in &pair.names {
+                by_name.entry(name.clone()).or_insert_with(|| Arc::clone(&pair.key));
+            }
+        }
+        store.by_name = by_name;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let mut store = self.store.lock().unwrap();
+        let due = store
+            .checked
+            .is_none_or(|checked| checked.elapsed().as_millis() as u64 >= self.reload_ms);
+        if self.reload_ms > 0 && due {
+            let _ = self.reload(&mut store, false);
+        }
+
+        if let Some(name) = client_hello.server_name() {
+            let name = name.to_ascii_lowercase();
+            if let Some(key) = store.by_name.get(&name) {
+                return Some(Arc::clone(key));
+            }
+            // A wildcard covers exactly one label.
+            if let Some(key) = name.split_once('.').and_then(|(_, parent)| store.by_name.get(&format!("*.{}", parent))) {
+                return Some(Arc::clone(key));
+            }
+        }
+        store.default.as_ref().map(|pair| Arc::clone(&pair.key))
+    }
+}
+
+// TLS 1.2 and 1.3 with the listener's certificates and ALPN protocols.
+pub fn server_config(listener: &ListenerConfig, reload_ms: u64) -> Result<Arc<ServerConfig>, String> {
+    let default_paths = match (&listener.tls_cert, &listener.tls_key) {
+        (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+        (None, None) => None,
+        _ => return Err(String::from("tls_cert and tls_key go together")),
+    };
+    let resolver = SniResolver::new(default_paths, listener.tls_cert_dir.as_ref().map(PathBuf::from), reload_ms)?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(&[&rustls::version::TLS13, &rustls::version::TLS12])
+        .map_err(|e| e.to_string())?;
+    let builder = match &listener.client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(Path::new(ca))?), provider);
+            let verifier = if listener.client_cert == ClientCert::Optional { verifier.allow_unauthenticated() } else { verifier };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+        None if listener.client_cert == ClientCert::Request => builder.with_client_cert_verifier(Arc::new(AnyClientCert(provider))),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = listener.alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+// Asks for a client certificate without requiring one or checking its chain;
+// routes with client_ca do that. The handshake signature is still checked,
+// so the client holds the certificate's key.
+#[derive(Debug)]
+struct AnyClientCert(Arc<CryptoProvider>);
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Whether the certificates a client presented chain up to one of `roots`.
+pub fn verify_client_chain(certs: &[CertificateDer<'_>], roots: &RootCertStore) -> bool {
+    let Some((end_entity, intermediates)) = certs.split_first() else {
+        return false;
+    };
+    let Ok(cert) = webpki::EndEntityCert::try_from(end_entity) else {
+        return false;
+    };
+    cert.verify_for_usage(
+        ring::default_provider().signature_verification_algorithms.all,
+        &roots.roots,
+        intermediates,
+        UnixTime::now(),
+        webpki::KeyUsage::client_auth(),
+        None,
+        None,
+    )
+    .is_ok()
+}
+
+// What backends are told about a verified client certificate:
+// Hash=<SHA-256 of the DER, hex>;Subject="<subject DN, e.g. CN=a, O=b>".
+pub fn client_cert_summary(cert: &CertificateDer<'_>) -> String {
+    let hash: String = ::ring::digest::digest(&::ring::digest::SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let subject = X509Certificate::from_der(cert)
+        .map(|(_, cert)| cert.subject().to_string())
+        .unwrap_or_default();
+    format!("Hash={};Subject=\"{}\"", hash, subject.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// tls_verify = false: any backend certificate is accepted, but the handshake
+// signatures are still checked.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// TLS to a pool's backends: verified against tls_ca unless tls_verify is
+// off, and with a client certificate when the pool has one.
+pub fn client_config(pool: &PoolConfig) -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(&[&rustls::version::TLS13, &rustls::version::TLS12])
+        .map_err(|e| e.to_string())?;
+    let builder = if pool.tls_verify {
+        let ca = pool
+            .tls_ca
+            .as_ref()
+            .ok_or("tls_ca is needed unless tls_verify = false")?;
+        builder.with_root_certificates(load_roots(Path::new(ca))?)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+    };
+    let config = match (&pool.tls_client_cert, &pool.tls_client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(Path::new(cert))?, load_key(Path::new(key))?)
+            .map_err(|e| format!("{} does not go with {}: {}", key, cert, e))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(String::from("tls_client_cert and tls_client_key go together")),
+    };
+    if let Some(name) = &pool.tls_server_name {
+        ServerName::try_from(name.clone()).map_err(|_| format!("invalid tls_server_name: {}", name))?;
+    }
+    Ok(Arc::new(config))
+}
+
+// Starts a TLS session on a freshly connected backend socket. The handshake
+// runs as the socket allows, through flush_plaintext and read_plaintext.
+// Without tls_server_name the backend's IP is verified and no SNI is sent.
+pub fn backend_tls(pool: &PoolConfig, server: &[u8; 6]) -> Result<ClientConnection, String> {
+    let config = pool.tls_config.as_ref().ok_or("pool does not use TLS")?;
+    let server_name = match &pool.tls_server_name {
+        Some(name) => ServerName::try_from(name.clone()).map_err(|e| e.to_string())?,
+        None => ServerName::from(IpAddr::V4(Ipv4Addr::new(server[0], server[1], server[2], server[3]))),
+    };
+    ClientConnection::new(Arc::clone(config), server_name).map_err(|e| e.to_string())
+}
+
+// A socket as rustls sees it in read_tls and write_tls.
+pub struct FdIo(pub RawFd);
+
+impl Read for FdIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+impl Write for FdIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { libc::write(self.0, buf.as_ptr() as *const _, buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes `out` through `conn` to a non-blocking socket. Plaintext stays in
+// `out` until the records carrying it are written, so an empty `out` still
+// means everything reached the socket; `sent` counts the bytes at its front
+// already handed to `conn`. Ok(false) means the socket stopped accepting.
+pub fn flush_plaintext<D>(conn: &mut ConnectionCommon<D>, fd: RawFd, out: &mut Vec<u8>, sent: &mut usize) -> io::Result<bool> {
+    loop {
+        while conn.wants_write() {
+            match conn.write_tls(&mut FdIo(fd)) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        out.drain(..*sent);
+        *sent = 0;
+        if out.is_empty() || conn.is_handshaking() {
+            return Ok(true);
+        }
+        match conn.writer().write(out)? {
+            0 => return Ok(true),
+            n => *sent = n,
+        }
+    }
+}
+
+// What a read from a client or backend produced.
+pub enum ClientRead {
+    // Plaintext was appended to the buffer.
+    Data,
+    // Nothing to pass on yet, e.g. only handshake records arrived.
+    WouldBlock,
+    Eof,
+    Failed,
+}
+
+// Reads whatever the socket has, decrypts it and appends all the plaintext
+// that is now available to `out`. Records the connection has to send in
+// reply (handshake, alerts) are left for the caller to write.
+pub fn read_plaintext<D>(conn: &mut ConnectionCommon<D>, fd: RawFd, out: &mut Vec<u8>) -> ClientRead {
+    let eof = match conn.read_tls(&mut FdIo(fd)) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return ClientRead::WouldBlock,
+        Err(_) => return ClientRead::Failed,
+    };
+    if conn.process_new_packets().is_err() {
+        return ClientRead::Failed;
+    }
+
+    let start = out.len();
+    let mut closed = eof;
+    loop {
+        let len = out.len();
+        out.resize(len + PLAINTEXT_CHUNK, 0);
+        match conn.reader().read(&mut out[len..]) {
+            Ok(0) => {
+                // close_notify.
+                out.truncate(len);
+                closed = true;
+                break;
+            }
+            Ok(n) => out.truncate(len + n),
+            Err(e) => {
+                out.truncate(len);
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    closed = true;
+                }
+                break;
+            }
+        }
+    }
+
+    if out.len() > start {
+        ClientRead::Data
+    } else if closed {
+        ClientRead::Eof
+    } else {
+        ClientRead::WouldBlock
+    }
+}
+
+// The largest ClientHello buffered while looking for its SNI.
+const MAX_CLIENT_HELLO: usize = 65536;
+
+#[derive(Debug, PartialEq)]
+pub enum ClientHelloSni {
+    // More bytes are needed.
+    Incomplete,
+    // The name the client asked for, if any.
+    Done(Option<String>),
+    Invalid,
+}
+
+// Finds the server name in a ClientHello without decrypting anything. The
+// hello may be split across several handshake records.
+pub fn client_hello_sni(buf: &[u8]) -> ClientHelloSni {
+    // Reassemble the handshake message from the records' fragments.
+    let mut handshake: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    loop {
+        if handshake.len() >= 4 {
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake[0] != 1 || len + 4 > MAX_CLIENT_HELLO {
+                return ClientHelloSni::Invalid;
+            }
+            if handshake.len() >= len + 4 {
+                return match parse_client_hello(&handshake[4..len + 4]) {
+                    Some(sni) => ClientHelloSni::Done(sni),
+                    None => ClientHelloSni::Invalid,
+                };
+            }
+        }
+        if buf.len() < pos + 5 {
+            break;
+        }
+        // ContentType handshake (22), then a 3.x version.
+        if buf[pos] != 22 || buf[pos + 1] != 3 {
+            return ClientHelloSni::Invalid;
+        }
+        let len = u16::from_be_bytes([buf[pos + 3], buf[pos + 4]]) as usize;
+        if buf.len() < pos + 5 + len {
+            break;
+        }
+        handshake.extend_from_slice(&buf[pos + 5..pos + 5 + len]);
+        pos += 5 + len;
+    }
+    if buf.len() > MAX_CLIENT_HELLO {
+        ClientHelloSni::Invalid
+    } else {
+        ClientHelloSni::Incomplete
+    }
+}
+
+fn take<'a>(body: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if body.len() < n {
+        return None;
+    }
+    let (head, rest) = body.split_at(n);
+    *body = rest;
+    Some(head)
+}
+
+// A field behind a big-endian length of `len_bytes` bytes.
+fn take_vec<'a>(body: &mut &'a [u8], len_bytes: usize) -> Option<&'a [u8]> {
+    let len = take(body, len_bytes)?.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+    take(body, len)
+}
+
+// The SNI host_name in a ClientHello body; None if it is malformed.
+fn parse_client_hello(mut body: &[u8]) -> Option<Option<String>> {
+    let body = &mut body;
+    take(body, 2 + 32)?; // legacy_version, random
+    take_vec(body, 1)?; // legacy_session_id
+    take_vec(body, 2)?; // cipher_suites
+    take_vec(body, 1)?; // legacy_compression_methods
+    if body.is_empty() {
+        return Some(None);
+    }
+    let mut extensions = take_vec(body, 2)?;
+    while !extensions.is_empty() {
+        let ext_type = take(&mut extensions, 2)?;
+        let mut data = take_vec(&mut extensions, 2)?;
+        if ext_type != [0, 0] {
+            continue;
+        }
+        let mut names = take_vec(&mut data, 2)?;
+        while !names.is_empty() {
+            let name_type = take(&mut names, 1)?[0];
+            let name = take_vec(&mut names, 2)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|name| Some(name.to_ascii_lowercase()));
+            }
+        }
+        return Some(None);
+    }
+    Some(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    // A ClientHello handshake message, with a server_name extension if `sni` is set.
+    fn hello(sni: Option<&str>) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // legacy_session_id
+        body.extend(vec16(&[0x13, 0x01]));
+        body.extend_from_slice(&[1, 0]);
+        let mut extensions = Vec::new();
+        // supported_versions comes first so the parser has to skip it.
+        extensions.extend_from_slice(&[0, 43]);
+        extensions.extend(vec16(&[2, 3, 4]));
+        if let Some(sni) = sni {
+            let mut name = vec![0];
+            name.extend(vec16(sni.as_bytes()));
+            extensions.extend_from_slice(&[0, 0]);
+            extensions.extend(vec16(&vec16(&name)));
+        }
+        body.extend(vec16(&extensions));
+        let mut message = vec![1];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+        message
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let mut out = vec![22, 3, 1];
+        out.extend(vec16(fragment));
+        out
+    }
+
+    fn done(name: &str) -> ClientHelloSni {
+        ClientHelloSni::Done(Some(name.to_string()))
+    }
+
+    #[test]
+    fn finds_the_server_name() {
+        assert_eq!(client_hello_sni(&record(&hello(Some("Example.COM")))), done("example.com"));
+    }
+
+    #[test]
+    fn hello_without_sni() {
+        assert_eq!(client_hello_sni(&record(&hello(None))), ClientHelloSni::Done(None));
+    }
+
+    #[test]
+    fn truncated_record_needs_more_bytes() {
+        let buf = record(&hello(Some("example.com")));
+        for len in [0, 3, 5, buf.len() - 1] {
+            assert_eq!(client_hello_sni(&buf[..len]), ClientHelloSni::Incomplete, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn hello_split_across_two_reads() {
+        // Each read ends partway through a record.
+        let message = hello(Some("example.com"));
+        let (first, second) = message.split_at(20);
+        let mut buf = record(first);
+        buf.extend(record(second));
+        let split = buf.len() - 10;
+        assert_eq!(client_hello_sni(&buf[..split]), ClientHelloSni::Incomplete);
+        assert_eq!(client_hello_sni(&buf), done("example.com"));
+    }
+
+    #[test]
+    fn lengths_that_overrun_the_message() {
+        let message = hello(Some("example.com"));
+        // The server name's own length, then the extensions block's length.
+        let name_len = message.len() - "example.com".len() - 2;
+        let extensions_len = 4 + 2 + 32 + 1 + 4 + 2;
+        for at in [name_len, extensions_len] {
+            let mut message = message.clone();
+            message[at..at + 2].copy_from_slice(&0xfff0u16.to_be_bytes());
+            assert_eq!(client_hello_sni(&record(&message)), ClientHelloSni::Invalid, "length at {}", at);
+        }
+    }
+
+    #[test]
+    fn handshake_length_past_the_limit() {
+        let mut message = hello(Some("example.com"));
+        message[1..4].copy_from_slice(&[0x10, 0, 0]);
+        assert_eq!(client_hello_sni(&record(&message)), ClientHelloSni::Invalid);
+    }
+
+    #[test]
+    fn not_a_handshake_record() {
+        let mut buf = record(&hello(Some("example.com")));
+        buf[0] = 23;
+        assert_eq!(client_hello_sni(&buf), ClientHelloSni::Invalid);
+        assert_eq!(client_hello_sni(b"GET / HTTP/1.1\r\n"), ClientHelloSni::Invalid);
+    }
+}
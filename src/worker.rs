@@ -1,19 +1,34 @@
-use http::{request, Version};
+use http::Version;
 use libc::*;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{CStr,CString};
 // use std::io::Read;
 use std::mem::{self, zeroed};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::os::fd::RawFd;
 use std::ptr;
-use http::{Request, header::{HeaderName, HeaderValue}};
-use httparse::{Request as HttpParseRequest, Status};
+use std::sync::Arc;
+use http::{Method, Request, Response, header::{self, HeaderMap, HeaderName, HeaderValue}};
+use httparse::{Request as HttpParseRequest, Response as HttpParseResponse, Status};
+use rustls::{ClientConnection, ServerConfig, ServerConnection};
+
+use crate::set_nonblocking;
+use crate::config::{Config, HostHeader, ListenerConfig, ListenerMode, DEFAULT_POOL};
+use crate::forwarded::add_forwarded_headers;
+use crate::framing::{BodyFraming, add_chunked_coding, collapse_content_length, encode_chunk, encode_last_chunk, normalize_chunked};
+use crate::http_error::{direct_response, error_response};
+use crate::rewrite::{apply_rules, expand, new_request_id, Vars};
+use crate::routing::{is_known_host, normalize_host, normalize_target, request_host, route_request, Route, RouteAction};
+use crate::tls::{
+    backend_tls, client_cert_summary, client_hello_sni, flush_plaintext, read_plaintext, verify_client_chain,
+    ClientHelloSni, ClientRead, FdIo,
+};
 
 extern crate queues;
-// use queues::*;
+use queues::{IsQueue, Queue};
 
 const SOCK_PATH: &str = "/tmp/test1.sock";
+const MAX_RESPONSE_HEAD: usize = 65536;
 
 fn ev_set(
     kev: &mut libc::kevent,
@@ -66,6 +81,112 @@ fn del_fd_to_kqueue(kq: i32, fd: usize) {
     }
 }
 
+fn add_fd_write_to_kqueue(kq: i32, fd: usize) {
+    unsafe {
+        let mut ev: kevent = zeroed();
+        ev_set(
+            &mut ev,
+            fd,
+            EVFILT_WRITE as i32,
+            EV_ADD | EV_ENABLE,
+            0,
+            0,
+            ptr::null_mut(),
+        );
+
+        let _ = kevent(kq, &ev, 1, ptr::null_mut(), 0, ptr::null());
+    }
+}
+
+fn del_fd_write_to_kqueue(kq: i32, fd: usize) {
+    unsafe {
+        let mut ev: kevent = zeroed();
+        ev_set(
+            &mut ev,
+            fd,
+            EVFILT_WRITE as i32,
+            EV_DELETE,
+            0,
+            0,
+            ptr::null_mut(),
+        );
+
+        let _ = kevent(kq, &ev, 1, ptr::null_mut(), 0, ptr::null());
+    }
+}
+
+// Queues an EVFILT_USER event for `fd` that fires on the next kevent call.
+fn wake_client(kq: i32, fd: usize) {
+    unsafe {
+        let mut ev: kevent = zeroed();
+        ev_set(
+            &mut ev,
+            fd,
+            EVFILT_USER as i32,
+            EV_ADD | EV_ONESHOT,
+            NOTE_TRIGGER,
+            0,
+            ptr::null_mut(),
+        );
+
+        let _ = kevent(kq, &ev, 1, ptr::null_mut(), 0, ptr::null());
+    }
+}
+
+fn set_fd_timer(kq: i32, fd: usize, ms: u64) {
+    unsafe {
+        let mut ev: kevent = zeroed();
+        ev_set(
+            &mut ev,
+            fd,
+            EVFILT_TIMER as i32,
+            EV_ADD | EV_ENABLE | EV_ONESHOT,
+            0,
+            ms as intptr_t,
+            ptr::null_mut(),
+        );
+
+        let _ = kevent(kq, &ev, 1, ptr::null_mut(), 0, ptr::null());
+    }
+}
+
+fn del_fd_timer(kq: i32, fd: usize) {
+    unsafe {
+        let mut ev: kevent = zeroed();
+        ev_set(
+            &mut ev,
+            fd,
+            EVFILT_TIMER as i32,
+            EV_DELETE,
+            0,
+            0,
+            ptr::null_mut(),
+        );
+
+        let _ = kevent(kq, &ev, 1, ptr::null_mut(), 0, ptr::null());
+    }
+}
+
+fn send_to_conn_db(conn_db_sock_fd: i32, kq: i32, addr: sockaddr_un, addr_len: u32, msg: &[u8]) {
+    unsafe {
+        let db_conn_status = write(conn_db_sock_fd, msg.as_ptr() as *const _, msg.len());
+        if db_conn_status < 0 {
+            if connect(
+                conn_db_sock_fd,
+                &addr as *const _ as *const sockaddr,
+                addr_len,
+            ) < 0
+            {
+                panic!("connect failed");
+            }
+
+            add_fd_to_kqueue(kq, conn_db_sock_fd as usize);
+
+            write(conn_db_sock_fd, msg.as_ptr() as *const _, msg.len());
+        }
+    }
+}
+
 const INET_ADDRSTRLEN: usize = 16;
 const INET6_ADDRSTRLEN: usize = 46;
 unsafe extern "C" {
@@ -110,7 +231,24 @@ fn get_client_ip(fd: RawFd) -> Option<String> {
     }
 }
 
-fn modify_headers(mut request: Request<Vec<u8>>, fd: i32, server: [u8; 10]) -> Request<Vec<u8>> {
+fn get_local_port(fd: RawFd) -> Option<u16> {
+    unsafe {
+        let mut addr: sockaddr_storage = zeroed();
+        let mut len = size_of::<sockaddr_storage>() as socklen_t;
+
+        if getsockname(fd, &mut addr as *mut _ as *mut sockaddr, &mut len) != 0 {
+            return None;
+        }
+
+        match addr.ss_family as i32 {
+            AF_INET => Some(u16::from_be((*(&addr as *const _ as *const sockaddr_in)).sin_port)),
+            AF_INET6 => Some(u16::from_be((*(&addr as *const _ as *const sockaddr_in6)).sin6_port)),
+            _ => None,
+        }
+    }
+}
+
+fn server_addr(server: &[u8]) -> String {
     let mut host = String::new();
     for i in 0..3 {
         host.push_str(&server[i].to_string());
@@ -119,31 +257,87 @@ fn modify_headers(mut request: Request<Vec<u8>>, fd: i32, server: [u8; 10]) -> R
     host.push_str(&server[3].to_string());
     host.push(':');
     host.push_str(&(((server[4] as i32) << 8) | (server[5] as i32)).to_string());
-    request.headers_mut().insert(
-        HeaderName::from_static("host"),
-        HeaderValue::from_str(&host).unwrap(),
-    );
-    if let Some(client_ip) = get_client_ip(fd) {
-        if let Ok(header_value) = HeaderValue::from_str(&client_ip) {
-            request.headers_mut().insert(
-                HeaderName::from_static("x-forwarded-for"),
-                header_value,
-            );
+    host
+}
+
+// The origin-form target of a request.
+fn request_uri(uri: &http::Uri) -> String {
+    uri.path_and_query()
+        .map(|target| target.as_str().to_string())
+        .unwrap_or_else(|| String::from("/"))
+}
+
+fn modify_headers(mut request: Request<Vec<u8>>, fd: i32, server: [u8; 10], front_req: &REQ, config: &Config) -> Request<Vec<u8>> {
+    let pool = config.pool(&front_req.pool);
+    let client_host = request_host(&request).unwrap_or_default();
+    // Forwarding headers describe the client's view, so add them before Host
+    // is rewritten.
+    if let Some(client_ip) = get_client_ip(fd).and_then(|ip| ip.parse::<IpAddr>().ok()) {
+        let local_port = get_local_port(fd).unwrap_or(0);
+        let proto = if front_req.tls { "https" } else { "http" };
+        add_forwarded_headers(request.headers_mut(), client_ip, local_port, proto, config);
+    }
+    if let Some(name) = &config.client_cert_header {
+        request.headers_mut().remove(name);
+        if let Some(value) = front_req.client_cert.as_ref().and_then(|value| HeaderValue::from_str(value).ok()) {
+            request.headers_mut().insert(name.clone(), value);
         }
     }
 
+    let backend_addr = server_addr(&server);
+    let host = match &pool.host_header {
+        // HTTP/1.0 clients may not send a Host at all.
+        HostHeader::Preserve if request.headers().contains_key(header::HOST) => None,
+        HostHeader::Fixed(fixed) => HeaderValue::from_str(fixed).ok(),
+        _ => HeaderValue::from_str(&backend_addr).ok(),
+    };
+    if let Some(host) = host {
+        request.headers_mut().insert(HeaderName::from_static("host"), host);
+    }
+    // Any 100-continue was already answered while the body was buffered.
+    request.headers_mut().remove(header::EXPECT);
+    collapse_content_length(request.headers_mut());
+    let upgrade = requested_upgrade(&request);
+    strip_hop_by_hop(request.headers_mut());
+    if let Some(upgrade) = upgrade {
+        // Upgrade is hop-by-hop, but the backend has to see it to switch.
+        request.headers_mut().insert(header::UPGRADE, upgrade);
+        request.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+    let version = request.version();
+    add_via(request.headers_mut(), version, config);
+
+    let client_ip = get_client_ip(fd).unwrap_or_default();
+    let request_uri = request_uri(request.uri());
+    let vars = Vars {
+        client_ip: &client_ip,
+        backend_addr: &backend_addr,
+        request_id: &front_req.request_id,
+        host: &client_host,
+        request_uri: &request_uri,
+        scheme: if front_req.tls { "https" } else { "http" },
+    };
+    apply_rules(request.headers_mut(), &pool.request_headers, &vars);
+    if let Some(route) = front_req.route.and_then(|index| config.routes.get(index)) {
+        apply_rules(request.headers_mut(), &route.request_headers, &vars);
+    }
+
     request
 }
 
-fn parse_http_request(buffer: [u8; 1024]) -> Result<Request<Vec<u8>>, Box<dyn std::error::Error>> {
+// A parsed message head and its length, or `None` while it is incomplete.
+type ParsedHead<T> = Result<Option<(T, usize)>, Box<dyn std::error::Error>>;
+
+// Returns `None` until the whole request head has arrived.
+fn parse_request_head(buffer: &[u8]) -> ParsedHead<Request<()>> {
     let mut headers = [httparse::EMPTY_HEADER; 32];
     let mut req = HttpParseRequest::new(&mut headers);
 
-    let status = req.parse(&buffer)?;
+    let status = req.parse(buffer)?;
 
     let parsed_len = match status {
         Status::Complete(len) => len,
-        Status::Partial => return Err("Incomplete HTTP request".into()),
+        Status::Partial => return Ok(None),
     };
 
     let method = req.method.ok_or("Missing method")?;
@@ -163,13 +357,17 @@ fn parse_http_request(buffer: [u8; 1024]) -> Result<Request<Vec<u8>>, Box<dyn st
         builder = builder.header(header.name, header.value);
     }
 
-    let body = buffer[parsed_len..].to_vec();
+    Ok(Some((builder.body(())?, parsed_len)))
+}
+
+// `buffer` holds exactly one complete request, body included.
+fn parse_http_request(buffer: &[u8]) -> Result<Request<Vec<u8>>, Box<dyn std::error::Error>> {
+    let (request, parsed_len) = parse_request_head(buffer)?.ok_or("Incomplete HTTP request")?;
 
-    Ok(builder.body(body)?)
+    Ok(request.map(|_| buffer[parsed_len..].to_vec()))
 }
 
 fn serialize_request(req: Request<Vec<u8>>) -> REQ {
-    let mut buffer = [0u8; 1024];
     let mut vec = Vec::new();
 
     let request_line = format!(
@@ -192,277 +390,1891 @@ fn serialize_request(req: Request<Vec<u8>>) -> REQ {
     }
 
     vec.extend_from_slice(b"\r\n");
-    vec.extend_from_slice(&req.body());
-
-    let copy_len = vec.len().min(1024);
-    buffer[..copy_len].copy_from_slice(&vec[..copy_len]);
-    let mut termination_len = 0;
-    for i in 0..copy_len {
-        if buffer[i] != 0 {
-            buffer[i] = vec[i];
-        } else {
-            termination_len = i;
-            break;
-        }
+    vec.extend_from_slice(req.body());
+
+    REQ { req_data: vec, ..Default::default() }
+}
+
+fn connection_has_token(headers: &HeaderMap, token: &str) -> bool {
+    headers.get_all(header::CONNECTION).iter().any(|value| {
+        value
+            .to_str()
+            .map(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    })
+}
+
+// Headers that only describe one connection and must not be forwarded.
+// Transfer-Encoding is hop-by-hop too, but it is rewritten with the framing.
+const HOP_BY_HOP: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "te", "upgrade"];
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Connection can name further hop-by-hop headers; framing and Host are
+    // never dropped on its say-so.
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !matches!(name.as_str(), "" | "content-length" | "transfer-encoding" | "host"))
+        .collect();
+    for name in named.iter().map(|name| name.as_str()).chain(HOP_BY_HOP) {
+        headers.remove(name);
     }
-    print!("{} \n", termination_len);
+}
 
-    REQ { req_data: buffer, n: termination_len }
+fn add_via(headers: &mut HeaderMap, version: Version, config: &Config) {
+    if config.via.is_empty() {
+        return;
+    }
+    let protocol = if version == Version::HTTP_10 { "1.0" } else { "1.1" };
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", protocol, config.via)) {
+        headers.append(header::VIA, value);
+    }
 }
 
-fn when_identity_equals_conn_db_sock_fd(
-    conn_db_sock_fd: i32,
-    req_map: &mut HashMap<RawFd, REQ>,
-    server_client_mapping: *mut HashMap<RawFd, RawFd>,
-    kq: i32,
-    addr: sockaddr_un,
-    addr_len: u32,
-    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
-    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
-    conn_db_res_counter: &mut i32
-) {
-    unsafe {
-        *conn_db_res_counter += 1;
-        let mut buf = [0u8; 10];
-        let n = read(conn_db_sock_fd, buf.as_mut_ptr() as *mut _, 10);
-        if n > 0 {
-            let client_fd = RawFd::from_be_bytes(buf[6..10].try_into().unwrap());
-            let request = *req_map.get(&client_fd).unwrap();
-            // println!("{:?}.{:?}.{:?}.{:?}:{:?}.{:?}", buf[0], buf[1], buf[2], buf[3], buf[4], buf[5]);
-            let backend_services_fd = socket(AF_INET, SOCK_STREAM, IPPROTO_TCP);
-            if backend_services_fd < 0 {
-                panic!("Failed to create socket");
-            }
-
-            let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-            let sockaddr_in = sockaddr_in {
-                sin_len: mem::size_of::<sockaddr_in>() as u8,
-                sin_family: AF_INET as u8,
-                sin_port: u16::to_be(((buf[4] as u16) << 8) | (buf[5] as u16)),
-                sin_addr: in_addr {
-                    s_addr: u32::from(ip).to_be(),
-                },
-                sin_zero: [0; 8],
-            };
+// The protocol an HTTP/1.1 client asked to switch to with Upgrade.
+fn requested_upgrade<T>(request: &Request<T>) -> Option<HeaderValue> {
+    if request.version() != Version::HTTP_11 || !connection_has_token(request.headers(), "upgrade") {
+        return None;
+    }
+    request.headers().get(header::UPGRADE).cloned()
+}
 
-            let sockaddr_ptr = &sockaddr_in as *const sockaddr_in as *const sockaddr;
+fn wants_keep_alive(request: &Request<Vec<u8>>) -> bool {
+    match request.version() {
+        Version::HTTP_11 => !connection_has_token(request.headers(), "close"),
+        _ => connection_has_token(request.headers(), "keep-alive"),
+    }
+}
 
-            let ret = connect(
-                backend_services_fd,
-                sockaddr_ptr,
-                mem::size_of::<sockaddr_in>() as u32,
-            );
-            if ret < 0 {
-                del_fd_to_kqueue(kq, backend_services_fd as usize);
-                close(backend_services_fd);
-                
-                let mut conn_db_request = [0u8; 7];
-                conn_db_request[0] = 2;
-                for i in 1..7 {
-                    conn_db_request[i] = buf[i-1];
-                }
+// Returns `None` until the whole response head has arrived.
+fn parse_http_response(buffer: &[u8]) -> ParsedHead<Response<()>> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut res = HttpParseResponse::new(&mut headers);
 
-                let db_conn_status = write(
-                    conn_db_sock_fd,
-                    conn_db_request.as_ptr() as *const _,
-                    conn_db_request.len(),
-                );
-                if db_conn_status < 0 {
-                    if connect(
-                        conn_db_sock_fd,
-                        &addr as *const _ as *const sockaddr,
-                        addr_len,
-                    ) < 0
-                    {
-                        panic!("connect failed");
-                    }
+    let status = res.parse(buffer)?;
 
-                    // del_fd_to_kqueue(&kq, conn_db_sock_fd as usize);
-                    add_fd_to_kqueue(kq, conn_db_sock_fd as usize);
+    let parsed_len = match status {
+        Status::Complete(len) => len,
+        Status::Partial => return Ok(None),
+    };
 
-                    write(
-                        conn_db_sock_fd,
-                        conn_db_request.as_ptr() as *const _,
-                        conn_db_request.len(),
-                    );
-                }
+    let code = res.code.ok_or("Missing status code")?;
+    let version = match res.version {
+        Some(1) => Version::HTTP_11,
+        Some(0) => Version::HTTP_10,
+        _ => return Err("Unknown HTTP version".into()),
+    };
 
-                let mut request_bytes = [0u8; 7];
-                request_bytes[3..7].copy_from_slice(&client_fd.to_be_bytes());
-                write(
-                    conn_db_sock_fd,
-                    request_bytes.as_ptr() as *const _,
-                    request_bytes.len(),
-                );
-                let server: [u8; 6] = buf[..6].try_into().unwrap();
-                if let Some(fdi_set) = (*server_reqs_mapping).get(&server) {
-                    for &fdi in fdi_set {
-                        let mut request_bytes_i = [0u8; 7];
-                        request_bytes_i[3..7].copy_from_slice(&fdi.to_be_bytes());
-                        write(
-                            conn_db_sock_fd,
-                            request_bytes_i.as_ptr() as *const _,
-                            request_bytes_i.len(),
-                        );
-                    }
-                }
-            }
+    let mut builder = Response::builder()
+        .status(code)
+        .version(version);
 
-            
-            let modified_request = serialize_request(modify_headers(parse_http_request(request.req_data).unwrap(), client_fd, buf));
-            // println!("{:?}", &modified_request.req_data[..modified_request.n]);
-            let write_ret = write(
-                backend_services_fd,
-                modified_request.req_data.as_ptr() as *const _,
-                modified_request.n,
-            );
-            if write_ret < 0 {
-                close(backend_services_fd);
-                panic!("Failed to write to socket");
-            }
-            (*server_client_mapping).insert(backend_services_fd, client_fd);
-            (*fd_ip_mapping).insert(backend_services_fd, buf[..6].try_into().unwrap());
-            let server: [u8; 6] = buf[..6].try_into().unwrap();
-            (*server_reqs_mapping)
-                .entry(server)
-                .or_insert_with(HashSet::new)
-                .insert(client_fd);
-            add_fd_to_kqueue(kq, backend_services_fd as usize);
+    for header in res.headers.iter() {
+        builder = builder.header(header.name, header.value);
+    }
 
-            // write(front_req.client_fd, buf.as_ptr() as *const _, 1024);
-            // close(front_req.client_fd);
-            // del_fd_to_kqueue(kq, front_req.client_fd as usize);
-            // server_client_mapping.remove(&front_req.client_fd);
+    Ok(Some((builder.body(())?, parsed_len)))
+}
 
-            // del_fd_to_kqueue(kq, backend_services_fd as usize);
-        } else {
-            if connect(
-                conn_db_sock_fd,
-                &addr as *const _ as *const sockaddr,
-                addr_len,
-            ) < 0
-            {
-                panic!("connect failed");
-            }
-        }
+fn serialize_response_head(res: &Response<()>) -> Vec<u8> {
+    let mut vec = Vec::new();
+
+    let status_line = format!(
+        "{} {} {}\r\n",
+        match res.version() {
+            Version::HTTP_10 => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        },
+        res.status().as_u16(),
+        res.status().canonical_reason().unwrap_or("")
+    );
+    vec.extend_from_slice(status_line.as_bytes());
+
+    for (name, value) in res.headers() {
+        vec.extend_from_slice(name.as_str().as_bytes());
+        vec.extend_from_slice(b": ");
+        vec.extend_from_slice(value.as_bytes());
+        vec.extend_from_slice(b"\r\n");
     }
+
+    vec.extend_from_slice(b"\r\n");
+    vec
 }
 
-fn when_identity_else(
-    client_fd: i32,
-    conn_db_sock_fd: i32,
-    req_map: &mut HashMap<RawFd, REQ>,
-    server_client_mapping: *mut HashMap<RawFd, RawFd>,
-    kq: i32,
-    buf: [u8; 1024],
-    n: usize,
-    addr: sockaddr_un,
-    addr_len: u32,
-    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
-    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
-    server_counter: &mut i32,
-    client_counter: &mut i32
-) {
-    unsafe {
-        match (*server_client_mapping).get(&client_fd) {
-            Some(target_fd) => {
-                // println!("\n\n{:?}", buf);
-                // println!("b");
-                *server_counter += 1;
-                write(*target_fd, buf[..n].as_ptr() as *const _, n);
-                del_fd_to_kqueue(kq, *target_fd as usize);
-                (*server_client_mapping).remove(&target_fd);
-                if let Some(server_key) = (*fd_ip_mapping).get(&client_fd) {
-                    if let Some(fd_set) = (*server_reqs_mapping).get_mut(server_key) {
-                        fd_set.remove(&target_fd);
+fn backend_keeps_alive(res: &Response<()>) -> bool {
+    match res.version() {
+        Version::HTTP_11 => !connection_has_token(res.headers(), "close"),
+        _ => connection_has_token(res.headers(), "keep-alive"),
+    }
+}
+
+// Appends the part of `data` that belongs to the current response to the
+// client's output buffer, rewriting the response head on the way. Returns
+// whether the response is complete.
+fn relay_response(client: &mut ClientConn, data: &[u8], config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut pending = data.to_vec();
+    loop {
+        let framing = match client.framing.as_mut() {
+            Some(framing) => framing,
+            None => {
+                client.response_head.extend_from_slice(&pending);
+                let (mut response, head_len) = match parse_http_response(&client.response_head)? {
+                    Some(parsed) => parsed,
+                    None => {
+                        if client.response_head.len() > MAX_RESPONSE_HEAD {
+                            return Err("Response head too large".into());
+                        }
+                        return Ok(false);
                     }
-                }
-                close(*target_fd);
+                };
+                pending = client.response_head.split_off(head_len);
+                client.response_head.clear();
 
-                let mut conn_db_request = [1u8; 7];
-                let server = (*fd_ip_mapping).get(&client_fd).unwrap_or(&[0u8; 6]);
-                for i in 1..7 {
-                    conn_db_request[i] = server[i-1];
+                // Interim responses are passed on and the final one follows;
+                // HTTP/1.0 clients do not know them (RFC 9110 15.2).
+                let status = response.status().as_u16();
+                let version = response.version();
+                if (100..200).contains(&status) && status != 101 {
+                    if !client.http10 {
+                        strip_hop_by_hop(response.headers_mut());
+                        add_via(response.headers_mut(), version, config);
+                        client.out.extend_from_slice(&serialize_response_head(&response));
+                    }
+                    continue;
                 }
 
-                let db_conn_status = write(
-                    conn_db_sock_fd,
-                    conn_db_request.as_ptr() as *const _,
-                    conn_db_request.len(),
-                );
-                if db_conn_status < 0 {
-                    if connect(
-                        conn_db_sock_fd,
-                        &addr as *const _ as *const sockaddr,
-                        addr_len,
-                    ) < 0
-                    {
-                        panic!("connect failed");
-                    }
+                if status == 101 && !client.upgrade_requested {
+                    return Err("Unrequested 101 response".into());
+                }
+                let upgrade = response.headers().get(header::UPGRADE).cloned();
 
-                    // del_fd_to_kqueue(&kq, conn_db_sock_fd as usize);
-                    add_fd_to_kqueue(kq, conn_db_sock_fd as usize);
+                let framing = BodyFraming::for_response(&response, client.head_request)?;
+                let close_delimited = matches!(framing, BodyFraming::CloseDelimited);
+                let chunked = matches!(framing, BodyFraming::Chunked(_));
+                if chunked {
+                    // Transfer-Encoding wins; a stale Content-Length must not reach the client.
+                    response.headers_mut().remove(header::CONTENT_LENGTH);
+                } else {
+                    collapse_content_length(response.headers_mut());
+                }
 
-                    write(
-                        conn_db_sock_fd,
-                        conn_db_request.as_ptr() as *const _,
-                        conn_db_request.len(),
-                    );
+                // HTTP/1.0 clients can't read chunked bodies, so decode them; a
+                // body of unknown length is chunked for HTTP/1.1 clients so the
+                // connection can stay open.
+                client.dechunk_response = chunked && client.http10;
+                client.chunk_response = close_delimited && status != 101 && !client.http10;
+                if client.dechunk_response {
+                    response.headers_mut().remove(header::TRANSFER_ENCODING);
+                } else if client.chunk_response {
+                    add_chunked_coding(response.headers_mut());
                 }
-                
-                (*fd_ip_mapping).remove(&client_fd);
-                del_fd_to_kqueue(kq, client_fd as usize);
-                close(client_fd);
-            }
-            None => {
-                // println!("{:?}", buf);
-                // println!("a");
-                *client_counter += 1;
-                let mut request_bytes = [0u8; 7];
-                request_bytes[3..7].copy_from_slice(&client_fd.to_be_bytes());
-                let db_conn_status = write(
-                    conn_db_sock_fd,
-                    request_bytes.as_ptr() as *const _,
-                    request_bytes.len(),
-                );
-                if db_conn_status < 0 {
-                    if connect(
-                        conn_db_sock_fd,
-                        &addr as *const _ as *const sockaddr,
-                        addr_len,
-                    ) < 0
-                    {
-                        panic!("connect failed");
-                    }
 
-                    // del_fd_to_kqueue(&kq, conn_db_sock_fd as usize);
-                    add_fd_to_kqueue(kq, conn_db_sock_fd as usize);
+                client.reusable = !close_delimited && backend_keeps_alive(&response);
+                client.keep_alive = client.keep_alive
+                    && !client.dechunk_response
+                    && (!close_delimited || client.chunk_response)
+                    && !connection_has_token(response.headers(), "close");
 
-                    write(
-                        conn_db_sock_fd,
-                        request_bytes.as_ptr() as *const _,
-                        request_bytes.len(),
+                strip_hop_by_hop(response.headers_mut());
+                add_via(response.headers_mut(), version, config);
+                let vars = Vars {
+                    client_ip: &client.client_ip,
+                    backend_addr: &client.backend_addr,
+                    request_id: &client.request_id,
+                    host: &client.host,
+                    request_uri: &client.request_uri,
+                    scheme: if client.tls.is_some() { "https" } else { "http" },
+                };
+                apply_rules(response.headers_mut(), &config.pool(&client.pool).response_headers, &vars);
+                if let Some(route) = client.route.and_then(|index| config.routes.get(index)) {
+                    apply_rules(response.headers_mut(), &route.response_headers, &vars);
+                }
+                if status == 101 {
+                    // Everything after this head is a byte stream in both directions.
+                    client.tunnel = true;
+                    if let Some(upgrade) = upgrade {
+                        response.headers_mut().insert(header::UPGRADE, upgrade);
+                    }
+                    response.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+                } else {
+                    response.headers_mut().insert(
+                        header::CONNECTION,
+                        HeaderValue::from_static(if client.keep_alive { "keep-alive" } else { "close" }),
                     );
                 }
+                client.out.extend_from_slice(&serialize_response_head(&response));
+                client.framing.insert(framing)
+            }
+        };
 
-                req_map.insert(client_fd, REQ { req_data: buf, n: n });
+        let (used, done) = if client.dechunk_response {
+            framing.decode(&pending, &mut client.out)?
+        } else {
+            let (used, done) = framing.advance(&pending)?;
+            if client.chunk_response {
+                encode_chunk(&pending[..used], &mut client.out);
+            } else {
+                client.out.extend_from_slice(&pending[..used]);
             }
+            (used, done)
+        };
+        if done && used < pending.len() {
+            // The backend sent more than it framed; don't trust it again.
+            client.reusable = false;
         }
+        return Ok(done);
     }
 }
 
-#[derive(Clone, Copy)]
-struct REQ {
-    req_data: [u8; 1024],
-    n: usize
-}
-
-pub fn worker_loop(sock_fd: i32) {
+// Writes as much buffered output as the client accepts. When it stops
+// accepting, waits for EVFILT_WRITE and pauses the backend meanwhile.
+// Returns false if the client connection failed.
+fn flush_client(kq: i32, client_fd: RawFd, client: &mut ClientConn, config: &Config) -> bool {
+    if client.tls.is_some() {
+        return flush_tls_client(kq, client_fd, client, config);
+    }
+    unsafe {
+        while !client.out.is_empty() {
+            let n = write(client_fd, client.out.as_ptr() as *const _, client.out.len());
+            if n > 0 {
+                client.out.drain(..n as usize);
+                continue;
+            }
+            if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock {
+                wait_client_writable(kq, client_fd, client, config);
+                return true;
+            }
+            return false;
+        }
+        true
+    }
+}
+
+// Same as flush_client for a TLS client.
+fn flush_tls_client(kq: i32, client_fd: RawFd, client: &mut ClientConn, config: &Config) -> bool {
+    let tls = client.tls.as_mut().unwrap();
+    match flush_plaintext(tls, client_fd, &mut client.out, &mut client.tls_sent) {
+        Ok(true) => true,
+        Ok(false) => {
+            wait_client_writable(kq, client_fd, client, config);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// The client's socket is full. The backend waits, and the client's timer
+// closes both if the client takes no more output for that long.
+fn wait_client_writable(kq: i32, client_fd: RawFd, client: &ClientConn, config: &Config) {
+    add_fd_write_to_kqueue(kq, client_fd as usize);
+    set_fd_timer(kq, client_fd as usize, backend_timeout(client, config));
+    if let Some(backend_fd) = client.backend_fd {
+        del_fd_timer(kq, backend_fd as usize);
+        del_fd_to_kqueue(kq, backend_fd as usize);
+    }
+}
+
+// TLS records (a session ticket, say) still waiting for the socket.
+fn tls_pending(client: &ClientConn) -> bool {
+    client.tls.as_ref().is_some_and(|tls| tls.wants_write())
+}
+
+fn close_client(kq: i32, client_fd: RawFd, client_conns: &mut HashMap<RawFd, ClientConn>) {
+    unsafe {
+        if let Some(mut tls) = client_conns.remove(&client_fd).and_then(|client| client.tls) {
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut FdIo(client_fd));
+        }
+        del_fd_timer(kq, client_fd as usize);
+        del_fd_write_to_kqueue(kq, client_fd as usize);
+        del_fd_to_kqueue(kq, client_fd as usize);
+        close(client_fd);
+    }
+}
+
+// Called once the whole response has been written to the client.
+fn finish_response(kq: i32, client_fd: RawFd, client_conns: &mut HashMap<RawFd, ClientConn>, config: &Config) {
+    match client_conns.get_mut(&client_fd) {
+        Some(client) if client.keep_alive => {
+            client.in_flight = false;
+            client.response_done = false;
+            client.framing = None;
+            if client.pipeline.size() > 0 || client.pending_error.is_some() {
+                // More requests were pipelined; worker_loop dispatches the next.
+                wake_client(kq, client_fd as usize);
+            } else {
+                add_fd_to_kqueue(kq, client_fd as usize);
+                if client.request_buf.is_empty() {
+                    set_fd_timer(kq, client_fd as usize, config.keepalive_timeout_ms);
+                } else {
+                    set_fd_timer(kq, client_fd as usize, config.header_timeout_ms);
+                }
+            }
+        }
+        _ => close_client(kq, client_fd, client_conns),
+    }
+}
+
+// A backend connection that is still usable goes back to the idle
+// connections of `idle_pool`, the id of the pool whose request it served.
+fn release_backend(
+    backend_fd: RawFd,
+    idle_pool: Option<u8>,
+    conn_db_sock_fd: i32,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    backend_pool: *mut BackendPool,
+    config: &Config
+) -> Option<RawFd> {
+    unsafe {
+        let client_fd = (*server_client_mapping).remove(&backend_fd);
+        let server = (*fd_ip_mapping).remove(&backend_fd);
+        if let Some(server) = server {
+            if let (Some(fd_set), Some(client_fd)) = ((*server_reqs_mapping).get_mut(&server), client_fd) {
+                fd_set.remove(&client_fd);
+            }
+
+            let mut conn_db_request = [1u8; 7];
+            conn_db_request[1..7].copy_from_slice(&server);
+            send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_request);
+        }
+
+        match server.zip(idle_pool) {
+            Some((server, pool_id)) if (*backend_pool).idle_count(&(pool_id, server)) < config.pool_max_idle => {
+                // Stay registered for reads so a server-side close is noticed while idle.
+                (*backend_pool).put((pool_id, server), backend_fd);
+                set_fd_timer(kq, backend_fd as usize, config.pool_idle_timeout_ms);
+            }
+            _ => {
+                (*backend_pool).end_tls(backend_fd);
+                del_fd_timer(kq, backend_fd as usize);
+                del_fd_to_kqueue(kq, backend_fd as usize);
+                close(backend_fd);
+            }
+        }
+
+        client_fd
+    }
+}
+
+// Connects to a backend; the socket is made non-blocking once connected.
+fn connect_backend(server: &[u8; 6]) -> RawFd {
+    unsafe {
+        let backend_services_fd = socket(AF_INET, SOCK_STREAM, IPPROTO_TCP);
+        if backend_services_fd < 0 {
+            panic!("Failed to create socket");
+        }
+
+        let ip = Ipv4Addr::new(server[0], server[1], server[2], server[3]);
+        let sockaddr_in = sockaddr_in {
+            sin_len: mem::size_of::<sockaddr_in>() as u8,
+            sin_family: AF_INET as u8,
+            sin_port: u16::to_be(((server[4] as u16) << 8) | (server[5] as u16)),
+            sin_addr: in_addr {
+                s_addr: u32::from(ip).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+
+        let sockaddr_ptr = &sockaddr_in as *const sockaddr_in as *const sockaddr;
+
+        let ret = connect(
+            backend_services_fd,
+            sockaddr_ptr,
+            mem::size_of::<sockaddr_in>() as u32,
+        );
+        if ret < 0 {
+            close(backend_services_fd);
+            return -1;
+        }
+        set_nonblocking(backend_services_fd);
+
+        backend_services_fd
+    }
+}
+
+// Idle connections are kept apart by pool id as well as backend: pools that
+// share a server can still differ in TLS settings, and a connection made for
+// one must not carry another's requests.
+type PoolKey = (u8, [u8; 6]);
+
+// Idle keep-alive connections to each backend of each pool, most recently
+// used last, and the TLS sessions of backend connections in pools that use
+// TLS.
+#[derive(Default)]
+struct BackendPool {
+    idle: HashMap<PoolKey, Vec<RawFd>>,
+    idle_fds: HashMap<RawFd, PoolKey>,
+    tls: HashMap<RawFd, Box<ClientConnection>>
+}
+
+impl BackendPool {
+    fn idle_count(&self, key: &PoolKey) -> usize {
+        self.idle.get(key).map_or(0, |fds| fds.len())
+    }
+
+    fn put(&mut self, key: PoolKey, fd: RawFd) {
+        self.idle.entry(key).or_default().push(fd);
+        self.idle_fds.insert(fd, key);
+    }
+
+    fn contains(&self, fd: RawFd) -> bool {
+        self.idle_fds.contains_key(&fd)
+    }
+
+    fn remove(&mut self, fd: RawFd) {
+        if let Some(fds) = self.idle_fds.remove(&fd).and_then(|key| self.idle.get_mut(&key)) {
+            fds.retain(|&idle_fd| idle_fd != fd);
+        }
+    }
+
+    // Hands out the newest idle connection that the server has not closed.
+    fn take(&mut self, kq: i32, key: &PoolKey) -> Option<RawFd> {
+        unsafe {
+            while let Some(fd) = self.idle.get_mut(key).and_then(|fds| fds.pop()) {
+                self.idle_fds.remove(&fd);
+                del_fd_timer(kq, fd as usize);
+
+                let mut byte = 0u8;
+                let n = recv(fd, &mut byte as *mut u8 as *mut _, 1, MSG_PEEK | MSG_DONTWAIT);
+                if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock {
+                    return Some(fd);
+                }
+
+                self.close_idle(kq, fd);
+            }
+            None
+        }
+    }
+
+    fn close_idle(&mut self, kq: i32, fd: RawFd) {
+        unsafe {
+            self.remove(fd);
+            self.end_tls(fd);
+            del_fd_timer(kq, fd as usize);
+            del_fd_to_kqueue(kq, fd as usize);
+            close(fd);
+        }
+    }
+
+    // A TLS handshake with the backend is still under way. It keeps the
+    // deadline it started with: traffic does not re-arm the backend's timer.
+    fn handshaking(&self, fd: RawFd) -> bool {
+        self.tls.get(&fd).is_some_and(|tls| tls.is_handshaking())
+    }
+
+    // Says goodbye on a TLS backend connection that is about to be closed.
+    fn end_tls(&mut self, fd: RawFd) {
+        if let Some(mut tls) = self.tls.remove(&fd) {
+            tls.send_close_notify();
+            let _ = tls.write_tls(&mut FdIo(fd));
+        }
+    }
+}
+
+// Queues a request in the client's tunnel_out and writes what the backend
+// takes now; EVFILT_WRITE on the backend sends the rest. Returns false if
+// the backend connection failed.
+fn send_request(
+    kq: i32,
+    client_fd: RawFd,
+    backend_fd: RawFd,
+    data: &[u8],
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    backend_pool: &mut BackendPool
+) -> bool {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) => client,
+        None => return false,
+    };
+    client.tunnel_out.clear();
+    client.tunnel_out.extend_from_slice(data);
+    client.tunnel_tls_sent = 0;
+    flush_tunnel(kq, client_fd, backend_fd, client, backend_pool)
+}
+
+// Reads what a backend sent into `buf`, decrypting it for pools that use TLS.
+fn read_backend(backend_fd: RawFd, backend_pool: &mut BackendPool, buf: &mut Vec<u8>) -> ClientRead {
+    match backend_pool.tls.get_mut(&backend_fd) {
+        Some(tls) => read_plaintext(tls, backend_fd, buf),
+        None => read_socket(backend_fd, buf),
+    }
+}
+
+const MAX_UDP_DATAGRAM: usize = 65536;
+// Datagrams held per flow while conn_db picks its backend.
+const MAX_UDP_QUEUED: usize = 64;
+
+fn sockaddr_to_server(addr: &sockaddr_in) -> [u8; 6] {
+    let mut server = [0u8; 6];
+    server[..4].copy_from_slice(&addr.sin_addr.s_addr.to_ne_bytes());
+    server[4..].copy_from_slice(&addr.sin_port.to_ne_bytes());
+    server
+}
+
+fn server_to_sockaddr(server: &[u8; 6]) -> sockaddr_in {
+    sockaddr_in {
+        sin_len: mem::size_of::<sockaddr_in>() as u8,
+        sin_family: AF_INET as u8,
+        sin_port: u16::from_ne_bytes([server[4], server[5]]),
+        sin_addr: in_addr {
+            s_addr: u32::from_ne_bytes([server[0], server[1], server[2], server[3]]),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+// One client address talking to one backend through a UDP listener. The
+// flow has its own socket towards the backend, so replies arriving on it
+// belong to this client.
+struct UdpFlow {
+    listen_fd: RawFd,
+    client: [u8; 6],
+    server: Option<[u8; 6]>,
+    queued: Vec<Vec<u8>>
+}
+
+#[derive(Default)]
+struct UdpFlows {
+    by_client: HashMap<(RawFd, [u8; 6]), RawFd>,
+    flows: HashMap<RawFd, UdpFlow>
+}
+
+impl UdpFlows {
+    fn contains(&self, fd: RawFd) -> bool {
+        self.flows.contains_key(&fd)
+    }
+
+    // Closes the flow and gives its backend slot back to conn_db.
+    fn expire(&mut self, fd: RawFd, conn_db_sock_fd: i32, kq: i32, addr: sockaddr_un, addr_len: u32) {
+        if let Some(flow) = self.flows.remove(&fd) {
+            self.by_client.remove(&(flow.listen_fd, flow.client));
+            if let Some(server) = flow.server {
+                let mut conn_db_request = [1u8; 7];
+                conn_db_request[1..7].copy_from_slice(&server);
+                send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_request);
+            }
+        }
+        del_fd_timer(kq, fd as usize);
+        del_fd_to_kqueue(kq, fd as usize);
+        unsafe {
+            close(fd);
+        }
+    }
+
+    // conn_db answered for the flow whose socket is `fd`.
+    fn assign(&mut self, fd: RawFd, server: [u8; 6], conn_db_sock_fd: i32, kq: i32, addr: sockaddr_un, addr_len: u32) {
+        if server == [0u8; 6] {
+            // No backend available: the queued datagrams are dropped.
+            self.expire(fd, conn_db_sock_fd, kq, addr, addr_len);
+            return;
+        }
+        let flow = match self.flows.get_mut(&fd) {
+            Some(flow) => flow,
+            None => return,
+        };
+        flow.server = Some(server);
+
+        unsafe {
+            let backend_addr = server_to_sockaddr(&server);
+            if connect(fd, &backend_addr as *const _ as *const sockaddr, mem::size_of::<sockaddr_in>() as u32) < 0 {
+                self.expire(fd, conn_db_sock_fd, kq, addr, addr_len);
+                return;
+            }
+            for datagram in flow.queued.drain(..) {
+                send(fd, datagram.as_ptr() as *const _, datagram.len(), 0);
+            }
+        }
+        add_fd_to_kqueue(kq, fd as usize);
+    }
+}
+
+// Datagrams from clients on a UDP listener. New client addresses get a flow
+// and a backend from conn_db; later datagrams follow the same flow.
+fn when_udp_datagram(
+    listen_fd: RawFd,
+    pool: &str,
+    conn_db_sock_fd: i32,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    udp_flows: &mut UdpFlows,
+    config: &Config
+) {
+    unsafe {
+        let mut buf = vec![0u8; MAX_UDP_DATAGRAM];
+        loop {
+            let mut client_addr: sockaddr_in = zeroed();
+            let mut client_len = mem::size_of::<sockaddr_in>() as socklen_t;
+            let n = recvfrom(
+                listen_fd,
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+                0,
+                &mut client_addr as *mut _ as *mut sockaddr,
+                &mut client_len,
+            );
+            if n < 0 {
+                return;
+            }
+            let datagram = &buf[..n as usize];
+            let client = sockaddr_to_server(&client_addr);
+
+            if let Some(&flow_fd) = udp_flows.by_client.get(&(listen_fd, client)) {
+                let flow = udp_flows.flows.get_mut(&flow_fd).unwrap();
+                if flow.server.is_some() {
+                    send(flow_fd, datagram.as_ptr() as *const _, datagram.len(), 0);
+                } else if flow.queued.len() < MAX_UDP_QUEUED {
+                    flow.queued.push(datagram.to_vec());
+                }
+                set_fd_timer(kq, flow_fd as usize, config.udp_flow_timeout_ms);
+                continue;
+            }
+
+            let flow_fd = socket(AF_INET, SOCK_DGRAM, 0);
+            if flow_fd < 0 {
+                eprintln!("Failed to create UDP flow socket");
+                continue;
+            }
+            set_nonblocking(flow_fd);
+            udp_flows.by_client.insert((listen_fd, client), flow_fd);
+            udp_flows.flows.insert(flow_fd, UdpFlow {
+                listen_fd,
+                client,
+                server: None,
+                queued: vec![datagram.to_vec()]
+            });
+            set_fd_timer(kq, flow_fd as usize, config.udp_flow_timeout_ms);
+
+            // The flow socket stands in for a client fd in the conn_db request.
+            let mut request_bytes = [0u8; 7];
+            request_bytes[1] = config.pool_id(pool);
+            request_bytes[3..7].copy_from_slice(&flow_fd.to_be_bytes());
+            send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &request_bytes);
+        }
+    }
+}
+
+// Replies from a flow's backend go back to its client from the listener's
+// address.
+fn when_udp_reply(
+    flow_fd: RawFd,
+    conn_db_sock_fd: i32,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    udp_flows: &mut UdpFlows,
+    config: &Config
+) {
+    unsafe {
+        let (listen_fd, client) = match udp_flows.flows.get(&flow_fd) {
+            Some(flow) => (flow.listen_fd, flow.client),
+            None => return,
+        };
+        let client_addr = server_to_sockaddr(&client);
+        let mut buf = vec![0u8; MAX_UDP_DATAGRAM];
+        loop {
+            let n = recv(flow_fd, buf.as_mut_ptr() as *mut _, buf.len(), 0);
+            if n < 0 {
+                if std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock {
+                    // E.g. ECONNREFUSED after an ICMP port unreachable.
+                    udp_flows.expire(flow_fd, conn_db_sock_fd, kq, addr, addr_len);
+                }
+                return;
+            }
+            sendto(
+                listen_fd,
+                buf.as_ptr() as *const _,
+                n as usize,
+                0,
+                &client_addr as *const _ as *const sockaddr,
+                mem::size_of::<sockaddr_in>() as socklen_t,
+            );
+            set_fd_timer(kq, flow_fd as usize, config.udp_flow_timeout_ms);
+        }
+    }
+}
+
+fn send_error(kq: i32, client_fd: RawFd, status: u16, config: &Config) {
+    unsafe {
+        let response = error_response(status, config);
+        write(client_fd, response.as_ptr() as *const _, response.len());
+        del_fd_timer(kq, client_fd as usize);
+        del_fd_to_kqueue(kq, client_fd as usize);
+        close(client_fd);
+    }
+}
+
+fn when_identity_equals_conn_db_sock_fd(
+    conn_db_sock_fd: i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    conn_db_res_counter: &mut i32,
+    connect_attempts: &mut HashMap<RawFd, u32>,
+    backend_pool: &mut BackendPool,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    udp_flows: &mut UdpFlows,
+    config: &Config
+) {
+    unsafe {
+        *conn_db_res_counter += 1;
+        let mut buf = [0u8; 10];
+        let n = read(conn_db_sock_fd, buf.as_mut_ptr() as *mut _, 10);
+        if n > 0 {
+            let client_fd = RawFd::from_be_bytes(buf[6..10].try_into().unwrap());
+            let server: [u8; 6] = buf[..6].try_into().unwrap();
+            let mut conn_db_release = [1u8; 7];
+            conn_db_release[1..7].copy_from_slice(&server);
+
+            if udp_flows.contains(client_fd) {
+                udp_flows.assign(client_fd, server, conn_db_sock_fd, kq, addr, addr_len);
+                return;
+            }
+
+            let request = match req_map.get(&client_fd) {
+                Some(request) if client_conns.contains_key(&client_fd) => request,
+                _ => {
+                    // The client went away while we were waiting for conn_db.
+                    connect_attempts.remove(&client_fd);
+                    req_map.remove(&client_fd);
+                    if server != [0u8; 6] {
+                        send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_release);
+                    }
+                    return;
+                }
+            };
+
+            if server == [0u8; 6] {
+                connect_attempts.remove(&client_fd);
+                req_map.remove(&client_fd);
+                fail_client(kq, client_fd, 503, client_conns, config);
+                return;
+            }
+
+            // Raw TCP clients get a fresh connection and no request.
+            let mut idempotent = false;
+            let modified_request = if request.raw {
+                REQ::default()
+            } else {
+                let parsed_request = match parse_http_request(&request.req_data) {
+                    Ok(parsed_request) => parsed_request,
+                    Err(_) => {
+                        send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_release);
+                        connect_attempts.remove(&client_fd);
+                        req_map.remove(&client_fd);
+                        fail_client(kq, client_fd, 400, client_conns, config);
+                        return;
+                    }
+                };
+                idempotent = matches!(
+                    *parsed_request.method(),
+                    Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+                );
+                serialize_request(modify_headers(parsed_request, client_fd, buf, request, config))
+            };
+            // println!("{:?}", &modified_request.req_data);
+
+            // Prefer an idle pooled connection; one the server dropped just
+            // before our write is closed and the next one tried.
+            let mut backend_services_fd = -1;
+            let mut reused = false;
+            while let Some(pooled_fd) = if request.raw || request.fresh { None } else { backend_pool.take(kq, &(config.pool_id(&request.pool), server)) } {
+                if send_request(kq, client_fd, pooled_fd, &modified_request.req_data, client_conns, backend_pool) {
+                    backend_services_fd = pooled_fd;
+                    reused = true;
+                    break;
+                }
+                backend_pool.close_idle(kq, pooled_fd);
+            }
+
+            if backend_services_fd < 0 {
+                // println!("{:?}.{:?}.{:?}.{:?}:{:?}.{:?}", buf[0], buf[1], buf[2], buf[3], buf[4], buf[5]);
+                backend_services_fd = connect_backend(&server);
+                if backend_services_fd < 0 {
+                    let mut conn_db_request = [0u8; 7];
+                    conn_db_request[0] = 2;
+                    conn_db_request[1..7].copy_from_slice(&buf[..6]);
+                    send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_request);
+
+                    let attempts = connect_attempts.entry(client_fd).or_insert(0);
+                    *attempts += 1;
+                    if *attempts <= config.max_connect_retries {
+                        let mut request_bytes = [0u8; 7];
+                        request_bytes[1] = config.pool_id(&request.pool);
+                        request_bytes[3..7].copy_from_slice(&client_fd.to_be_bytes());
+                        send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &request_bytes);
+                    } else {
+                        eprintln!("Giving up on client {} after {} connect attempts", client_fd, attempts);
+                        connect_attempts.remove(&client_fd);
+                        req_map.remove(&client_fd);
+                        fail_client(kq, client_fd, 502, client_conns, config);
+                    }
+                    return;
+                }
+
+                // The server is up, so a failed handshake only fails this
+                // request. The request waits in tunnel_out until the
+                // handshake, driven by the backend's reads, is done.
+                let pool = config.pool(&request.pool);
+                if pool.tls {
+                    match backend_tls(pool, &server) {
+                        Ok(tls) => {
+                            backend_pool.tls.insert(backend_services_fd, Box::new(tls));
+                        }
+                        Err(e) => {
+                            eprintln!("TLS with {} failed: {}", server_addr(&server), e);
+                            close(backend_services_fd);
+                            send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_release);
+                            connect_attempts.remove(&client_fd);
+                            req_map.remove(&client_fd);
+                            fail_client(kq, client_fd, 502, client_conns, config);
+                            return;
+                        }
+                    }
+                }
+
+                if !request.raw && !send_request(kq, client_fd, backend_services_fd, &modified_request.req_data, client_conns, backend_pool) {
+                    backend_pool.end_tls(backend_services_fd);
+                    close(backend_services_fd);
+                    send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &conn_db_release);
+                    connect_attempts.remove(&client_fd);
+                    req_map.remove(&client_fd);
+                    fail_client(kq, client_fd, 502, client_conns, config);
+                    return;
+                }
+            }
+            connect_attempts.remove(&client_fd);
+            let front_req = req_map.remove(&client_fd);
+            let raw = front_req.as_ref().is_some_and(|request| request.raw);
+
+            (*server_client_mapping).insert(backend_services_fd, client_fd);
+            (*fd_ip_mapping).insert(backend_services_fd, server);
+            (*server_reqs_mapping)
+                .entry(server)
+                .or_default()
+                .insert(client_fd);
+            add_fd_to_kqueue(kq, backend_services_fd as usize);
+            if raw {
+                // Start relaying in both directions.
+                let mut resume = true;
+                if let Some(client) = client_conns.get_mut(&client_fd) {
+                    client.backend_fd = Some(backend_services_fd);
+                    client.tunnel_open = true;
+                    // A passthrough client's ClientHello is already buffered; if
+                    // the backend refuses it, reading from the backend says so.
+                    resume = flush_tunnel(kq, client_fd, backend_services_fd, client, backend_pool) && client.tunnel_out.is_empty();
+                }
+                if resume {
+                    add_fd_to_kqueue(kq, client_fd as usize);
+                }
+                let timeout = if backend_pool.handshaking(backend_services_fd) {
+                    config.backend_timeout_ms
+                } else {
+                    config.tunnel_idle_timeout_ms
+                };
+                set_fd_timer(kq, backend_services_fd as usize, timeout);
+            } else {
+                if let Some(client) = client_conns.get_mut(&client_fd) {
+                    client.retry = if reused && idempotent { front_req } else { None };
+                }
+                set_fd_timer(kq, backend_services_fd as usize, config.backend_timeout_ms);
+            }
+
+            // write(front_req.client_fd, buf.as_ptr() as *const _, 1024);
+            // close(front_req.client_fd);
+            // del_fd_to_kqueue(kq, front_req.client_fd as usize);
+            // server_client_mapping.remove(&front_req.client_fd);
+
+            // del_fd_to_kqueue(kq, backend_services_fd as usize);
+        } else {
+            if connect(
+                conn_db_sock_fd,
+                &addr as *const _ as *const sockaddr,
+                addr_len,
+            ) < 0
+            {
+                panic!("connect failed");
+            }
+        }
+    }
+}
+
+// Answers the client with an error unless part of the response has already
+// been sent, in which case all we can do is drop the connection.
+fn fail_client(kq: i32, client_fd: RawFd, status: u16, client_conns: &mut HashMap<RawFd, ClientConn>, config: &Config) {
+    match client_conns.get(&client_fd) {
+        Some(client) if client.framing.is_some() || client.raw || client.passthrough => close_client(kq, client_fd, client_conns),
+        Some(client) if client.tls.is_some() => fail_tls_client(kq, client_fd, status, client_conns, config),
+        _ => {
+            client_conns.remove(&client_fd);
+            send_error(kq, client_fd, status, config);
+        }
+    }
+}
+
+// The error goes out through the usual write path, since a full socket
+// buffer would otherwise cut the TLS records short. The timer closes the
+// connection if the client never reads it.
+fn fail_tls_client(kq: i32, client_fd: RawFd, status: u16, client_conns: &mut HashMap<RawFd, ClientConn>, config: &Config) {
+    let client = client_conns.get_mut(&client_fd).unwrap();
+    client.keep_alive = false;
+    client.in_flight = false;
+    client.backend_fd = None;
+    client.framing = Some(BodyFraming::Empty);
+    client.response_done = true;
+    client.out = error_response(status, config);
+    del_fd_to_kqueue(kq, client_fd as usize);
+    set_fd_timer(kq, client_fd as usize, config.header_timeout_ms);
+    if !flush_client(kq, client_fd, client, config) {
+        close_client(kq, client_fd, client_conns);
+    } else if client.out.is_empty() {
+        finish_response(kq, client_fd, client_conns, config);
+    }
+}
+
+fn when_identity_backend(
+    backend_fd: RawFd,
+    conn_db_sock_fd: i32,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    server_counter: &mut i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    backend_pool: &mut BackendPool,
+    config: &Config
+) {
+    unsafe {
+        *server_counter += 1;
+        let target_fd = *(*server_client_mapping).get(&backend_fd).unwrap();
+        let mut buf = Vec::new();
+        let was_handshaking = backend_pool.handshaking(backend_fd);
+        let read = read_backend(backend_fd, backend_pool, &mut buf);
+        if let Some(tls) = backend_pool.tls.get(&backend_fd) {
+            if tls.is_handshaking() && matches!(read, ClientRead::Eof | ClientRead::Failed) {
+                if let Some(server) = (*fd_ip_mapping).get(&backend_fd) {
+                    eprintln!("TLS handshake with {} failed", server_addr(server));
+                }
+            } else if tls.wants_write() || (was_handshaking && !tls.is_handshaking()) {
+                // Handshake replies, and then the request queued behind the
+                // handshake, go out as the socket takes them.
+                add_fd_write_to_kqueue(kq, backend_fd as usize);
+            }
+        }
+        let n = match read {
+            ClientRead::Data => buf.len() as isize,
+            ClientRead::Eof => 0,
+            // Only part of a TLS record has arrived.
+            ClientRead::WouldBlock => return,
+            ClientRead::Failed => -1,
+        };
+
+        let client = match client_conns.get_mut(&target_fd) {
+            Some(client) => client,
+            None => {
+                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+                return;
+            }
+        };
+        client.backend_fd = Some(backend_fd);
+        if let Some(server) = (*fd_ip_mapping).get(&backend_fd) {
+            client.backend_addr = server_addr(server);
+        }
+
+        if client.raw {
+            if n > 0 {
+                client.out.extend_from_slice(&buf[..n as usize]);
+                set_fd_timer(kq, backend_fd as usize, config.tunnel_idle_timeout_ms);
+            } else if n == 0 {
+                client.backend_eof = true;
+                del_fd_to_kqueue(kq, backend_fd as usize);
+            }
+            if n < 0 || !flush_client(kq, target_fd, client, config) || raw_half_close(target_fd, client, backend_pool) {
+                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+                close_client(kq, target_fd, client_conns);
+            }
+            return;
+        }
+
+        if n <= 0 {
+            release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+            client.backend_fd = None;
+            if let Some(mut retry) = client.retry.take() {
+                // The pooled connection was dead before it answered; try
+                // once more on a fresh one.
+                retry.fresh = true;
+                let mut request_bytes = [0u8; 7];
+                request_bytes[1] = config.pool_id(&retry.pool);
+                request_bytes[3..7].copy_from_slice(&target_fd.to_be_bytes());
+                send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &request_bytes);
+                req_map.insert(target_fd, retry);
+                return;
+            }
+            if n == 0 && matches!(client.framing, Some(BodyFraming::CloseDelimited)) {
+                // A close-delimited body ends here.
+                client.response_done = true;
+                if client.chunk_response {
+                    encode_last_chunk(&[], &mut client.out);
+                }
+                if !flush_client(kq, target_fd, client, config) {
+                    close_client(kq, target_fd, client_conns);
+                } else if client.out.is_empty() {
+                    finish_response(kq, target_fd, client_conns, config);
+                }
+            } else {
+                // The backend closed or failed before the response was complete.
+                fail_client(kq, target_fd, 502, client_conns, config);
+            }
+            return;
+        }
+
+        client.retry = None;
+        set_fd_timer(kq, backend_fd as usize, backend_timeout(client, config));
+        let done = match relay_response(client, &buf[..n as usize], config) {
+            Ok(done) => done,
+            Err(e) => {
+                eprintln!("Bad response from backend fd {}: {}", backend_fd, e);
+                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+                fail_client(kq, target_fd, 502, client_conns, config);
+                return;
+            }
+        };
+
+        if client.tunnel && !client.tunnel_open {
+            start_tunnel(kq, target_fd, backend_fd, client, backend_pool, config);
+        }
+
+        if done {
+            // A backend that answered before it took the whole request is not reused.
+            let reusable = client.reusable && client.tunnel_out.is_empty();
+            let idle_pool = reusable.then(|| config.pool_id(&client.pool));
+            client.tunnel_out.clear();
+            client.tunnel_tls_sent = 0;
+            client.backend_fd = None;
+            client.response_done = true;
+            release_backend(backend_fd, idle_pool, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+        }
+
+        let client = client_conns.get_mut(&target_fd).unwrap();
+        if !flush_client(kq, target_fd, client, config) {
+            if let Some(backend_fd) = client.backend_fd {
+                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+            }
+            close_client(kq, target_fd, client_conns);
+        } else if client.response_done && client.out.is_empty() {
+            finish_response(kq, target_fd, client_conns, config);
+        }
+    }
+}
+
+fn when_client_writable(
+    client_fd: RawFd,
+    conn_db_sock_fd: i32,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    backend_pool: &mut BackendPool,
+    config: &Config
+) {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) => client,
+        None => {
+            del_fd_write_to_kqueue(kq, client_fd as usize);
+            return;
+        }
+    };
+
+    if !flush_client(kq, client_fd, client, config) {
+        if let Some(backend_fd) = client.backend_fd {
+            release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+        }
+        close_client(kq, client_fd, client_conns);
+        return;
+    }
+    if !client.out.is_empty() || tls_pending(client) {
+        return;
+    }
+
+    // Drained: resume the backend, or wrap up if the response is complete.
+    del_fd_write_to_kqueue(kq, client_fd as usize);
+    del_fd_timer(kq, client_fd as usize);
+    if client.raw && client.backend_eof {
+        if raw_half_close(client_fd, client, backend_pool) {
+            if let Some(backend_fd) = client.backend_fd {
+                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+            }
+            close_client(kq, client_fd, client_conns);
+        }
+    } else if client.response_done {
+        finish_response(kq, client_fd, client_conns, config);
+    } else if let Some(backend_fd) = client.backend_fd {
+        add_fd_to_kqueue(kq, backend_fd as usize);
+        set_fd_timer(kq, backend_fd as usize, backend_timeout(client, config));
+    }
+}
+
+fn backend_timeout(client: &ClientConn, config: &Config) -> u64 {
+    if client.tunnel || client.raw {
+        config.tunnel_idle_timeout_ms
+    } else {
+        config.backend_timeout_ms
+    }
+}
+
+// The answer of a route that redirects or responds by itself, with the
+// route's response_header rules applied; None for routes to a pool.
+fn route_response(route: &Route, client: &ClientConn) -> Option<Vec<u8>> {
+    let vars = Vars {
+        client_ip: &client.client_ip,
+        backend_addr: "",
+        request_id: &client.request_id,
+        host: &client.host,
+        request_uri: &client.request_uri,
+        scheme: if client.tls.is_some() { "https" } else { "http" },
+    };
+    let mut headers = HeaderMap::new();
+    let (status, body) = match &route.action {
+        RouteAction::Proxy => return None,
+        RouteAction::Redirect(status, location) => {
+            let location = expand(location, &vars);
+            if let Ok(value) = HeaderValue::from_str(&location) {
+                headers.insert(header::LOCATION, value);
+            }
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+            (*status, format!("<html><body><a href=\"{}\">Moved</a></body></html>\n", location).into_bytes())
+        }
+        RouteAction::Respond { status, content_type, body } => {
+            if let Ok(value) = HeaderValue::from_str(content_type) {
+                headers.insert(header::CONTENT_TYPE, value);
+            }
+            (*status, body.clone())
+        }
+    };
+    apply_rules(&mut headers, &route.response_headers, &vars);
+    Some(direct_response(status, &headers, &body, client.keep_alive, client.head_request))
+}
+
+// Switches the client to tunnel mode after a 101: its reads go straight to
+// the backend from now on.
+fn start_tunnel(kq: i32, client_fd: RawFd, backend_fd: RawFd, client: &mut ClientConn, backend_pool: &mut BackendPool, config: &Config) {
+    client.tunnel_open = true;
+    client.keep_alive = false;
+    // Anything the client sent after the upgrade request already belongs to
+    // the new protocol.
+    client.tunnel_out.append(&mut client.request_buf);
+    client.request_framing = None;
+    client.pipeline = Queue::new();
+    client.pending_error = None;
+    // A failed write shows up as an error on the next backend read.
+    if flush_tunnel(kq, client_fd, backend_fd, client, backend_pool) && client.tunnel_out.is_empty() {
+        add_fd_to_kqueue(kq, client_fd as usize);
+    }
+    set_fd_timer(kq, backend_fd as usize, config.tunnel_idle_timeout_ms);
+}
+
+// Writes as much tunnelled client data as the backend accepts. When it stops
+// accepting, waits for EVFILT_WRITE on the backend and stops reading from the
+// client meanwhile. Returns false if the backend connection failed.
+fn flush_tunnel(kq: i32, client_fd: RawFd, backend_fd: RawFd, client: &mut ClientConn, backend_pool: &mut BackendPool) -> bool {
+    if let Some(tls) = backend_pool.tls.get_mut(&backend_fd) {
+        return match flush_plaintext(tls, backend_fd, &mut client.tunnel_out, &mut client.tunnel_tls_sent) {
+            Ok(true) => true,
+            Ok(false) => {
+                add_fd_write_to_kqueue(kq, backend_fd as usize);
+                del_fd_to_kqueue(kq, client_fd as usize);
+                true
+            }
+            Err(_) => false,
+        };
+    }
+    unsafe {
+        while !client.tunnel_out.is_empty() {
+            let n = write(backend_fd, client.tunnel_out.as_ptr() as *const _, client.tunnel_out.len());
+            if n > 0 {
+                client.tunnel_out.drain(..n as usize);
+                continue;
+            }
+            if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock {
+                add_fd_write_to_kqueue(kq, backend_fd as usize);
+                del_fd_to_kqueue(kq, client_fd as usize);
+                return true;
+            }
+            return false;
+        }
+        true
+    }
+}
+
+// Data the client sent through an open tunnel.
+fn when_tunnel_client_data(
+    client_fd: RawFd,
+    data: &[u8],
+    conn_db_sock_fd: i32,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    backend_pool: &mut BackendPool,
+    config: &Config
+) {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) => client,
+        None => return,
+    };
+    let backend_fd = match client.backend_fd {
+        Some(backend_fd) => backend_fd,
+        None => {
+            close_client(kq, client_fd, client_conns);
+            return;
+        }
+    };
+
+    client.tunnel_out.extend_from_slice(data);
+    if !flush_tunnel(kq, client_fd, backend_fd, client, backend_pool) {
+        release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+        close_client(kq, client_fd, client_conns);
+        return;
+    }
+    if !backend_pool.handshaking(backend_fd) {
+        set_fd_timer(kq, backend_fd as usize, config.tunnel_idle_timeout_ms);
+    }
+}
+
+// The backend can take more of a request or of tunnelled client data.
+fn when_backend_writable(
+    backend_fd: RawFd,
+    conn_db_sock_fd: i32,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    backend_pool: &mut BackendPool,
+    config: &Config
+) {
+    unsafe {
+        let client_fd = *(*server_client_mapping).get(&backend_fd).unwrap();
+        let client = match client_conns.get_mut(&client_fd) {
+            Some(client) => client,
+            None => {
+                del_fd_write_to_kqueue(kq, backend_fd as usize);
+                return;
+            }
+        };
+
+        if !flush_tunnel(kq, client_fd, backend_fd, client, backend_pool) {
+            release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+            if client.tunnel_open {
+                close_client(kq, client_fd, client_conns);
+            } else {
+                fail_client(kq, client_fd, 502, client_conns, config);
+            }
+            return;
+        }
+        if let Some(tls) = backend_pool.tls.get(&backend_fd).filter(|tls| tls.is_handshaking()) {
+            // The rest waits for the backend's side of the handshake.
+            if !tls.wants_write() {
+                del_fd_write_to_kqueue(kq, backend_fd as usize);
+            }
+            return;
+        }
+        // Taking data counts as progress, like answering does.
+        set_fd_timer(kq, backend_fd as usize, backend_timeout(client, config));
+        if client.tunnel_out.is_empty() {
+            del_fd_write_to_kqueue(kq, backend_fd as usize);
+            // The whole request is out; its client stays paused until answered.
+            if !client.tunnel_open {
+                return;
+            }
+            if !client.client_eof {
+                add_fd_to_kqueue(kq, client_fd as usize);
+            } else if raw_half_close(client_fd, client, backend_pool) {
+                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+                close_client(kq, client_fd, client_conns);
+            }
+        }
+    }
+}
+
+// Passes each side's EOF on to the other once the data buffered for it has
+// been written. Returns true when both directions are finished.
+fn raw_half_close(client_fd: RawFd, client: &mut ClientConn, backend_pool: &mut BackendPool) -> bool {
+    unsafe {
+        if client.backend_eof && client.out.is_empty() && !client.client_shut {
+            shutdown(client_fd, SHUT_WR);
+            client.client_shut = true;
+        }
+        if client.client_eof && client.tunnel_out.is_empty() && !client.backend_shut {
+            if let Some(backend_fd) = client.backend_fd {
+                backend_pool.end_tls(backend_fd);
+                shutdown(backend_fd, SHUT_WR);
+            }
+            client.backend_shut = true;
+        }
+        client.client_shut && client.backend_shut
+    }
+}
+
+// A raw TCP client sent EOF: its data is done, but replies may still come.
+fn when_raw_client_eof(
+    client_fd: RawFd,
+    conn_db_sock_fd: i32,
+    server_client_mapping: *mut HashMap<RawFd, RawFd>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    fd_ip_mapping: *mut HashMap<RawFd, [u8; 6]>,
+    server_reqs_mapping: *mut HashMap<[u8; 6], HashSet<RawFd>>,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    backend_pool: &mut BackendPool,
+    config: &Config
+) {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) => client,
+        None => return,
+    };
+    client.client_eof = true;
+    del_fd_to_kqueue(kq, client_fd as usize);
+    if raw_half_close(client_fd, client, backend_pool) {
+        if let Some(backend_fd) = client.backend_fd {
+            release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, server_client_mapping, fd_ip_mapping, server_reqs_mapping, backend_pool, config);
+        }
+        close_client(kq, client_fd, client_conns);
+    }
+}
+
+fn read_socket(fd: RawFd, buf: &mut Vec<u8>) -> ClientRead {
+    unsafe {
+        buf.resize(16384, 0);
+        let n = read(fd, buf.as_mut_ptr() as *mut _, buf.len());
+        if n > 0 {
+            buf.truncate(n as usize);
+            return ClientRead::Data;
+        }
+        buf.clear();
+        if n == 0 {
+            return ClientRead::Eof;
+        }
+        if std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock {
+            return ClientRead::WouldBlock;
+        }
+        ClientRead::Failed
+    }
+}
+
+// Reads what a client sent into `buf`, decrypting it on TLS listeners.
+fn read_client(kq: i32, client_fd: RawFd, client_conns: &mut HashMap<RawFd, ClientConn>, buf: &mut Vec<u8>, config: &Config) -> ClientRead {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) if client.tls.is_some() => client,
+        _ => return read_socket(client_fd, buf),
+    };
+
+    let read = read_plaintext(client.tls.as_mut().unwrap(), client_fd, buf);
+    // Handshake messages and alerts go out with the rest of the client's output.
+    if !flush_client(kq, client_fd, client, config) {
+        return ClientRead::Failed;
+    }
+    read
+}
+
+fn accept_client(
+    kq: i32,
+    listen_fd: RawFd,
+    pool: &str,
+    tls: Option<&Arc<ServerConfig>>,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) -> Option<RawFd> {
+    unsafe {
+        let client_fd = accept(listen_fd, ptr::null_mut(), ptr::null_mut());
+        if client_fd < 0 {
+            return None;
+        }
+        set_nonblocking(client_fd);
+        let tls = match tls.map(|tls| ServerConnection::new(Arc::clone(tls))) {
+            Some(Ok(conn)) => Some(Box::new(conn)),
+            Some(Err(_)) => {
+                close(client_fd);
+                return None;
+            }
+            None => None,
+        };
+        client_conns.insert(client_fd, ClientConn { default_pool: pool.to_string(), tls, ..Default::default() });
+        add_fd_to_kqueue(kq, client_fd as usize);
+        // A client that connects and sends nothing (or never finishes its
+        // TLS handshake) is dropped after header_timeout_ms.
+        set_fd_timer(kq, client_fd as usize, config.header_timeout_ms);
+        Some(client_fd)
+    }
+}
+
+// Accepts a client on a TCP listener and asks conn_db for a backend right
+// away; the client is read from once that backend is connected.
+fn accept_raw_client(
+    kq: i32,
+    listen_fd: RawFd,
+    pool: &str,
+    conn_db_sock_fd: i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    addr: sockaddr_un,
+    addr_len: u32,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) {
+    let client_fd = match accept_client(kq, listen_fd, pool, None, client_conns, config) {
+        Some(client_fd) => client_fd,
+        None => return,
+    };
+    request_raw_backend(kq, client_fd, pool, conn_db_sock_fd, req_map, addr, addr_len, client_conns, config);
+}
+
+// Turns the client into a raw byte stream to a backend of `pool`, which is
+// asked for now; the client is read from again once it is connected.
+fn request_raw_backend(
+    kq: i32,
+    client_fd: RawFd,
+    pool: &str,
+    conn_db_sock_fd: i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    addr: sockaddr_un,
+    addr_len: u32,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) {
+    del_fd_timer(kq, client_fd as usize);
+    del_fd_to_kqueue(kq, client_fd as usize);
+
+    let client = client_conns.get_mut(&client_fd).unwrap();
+    client.raw = true;
+    client.in_flight = true;
+    client.tunnel = true;
+    client.pool = pool.to_string();
+
+    let mut request_bytes = [0u8; 7];
+    request_bytes[1] = config.pool_id(pool);
+    request_bytes[3..7].copy_from_slice(&client_fd.to_be_bytes());
+    send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &request_bytes);
+
+    req_map.insert(client_fd, REQ { raw: true, pool: pool.to_string(), ..Default::default() });
+}
+
+// Accepts a client on a passthrough listener. It is read from until its
+// ClientHello is complete.
+fn accept_passthrough_client(
+    kq: i32,
+    listen_fd: RawFd,
+    listener: usize,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) {
+    let client_fd = match accept_client(kq, listen_fd, "", None, client_conns, config) {
+        Some(client_fd) => client_fd,
+        None => return,
+    };
+    let client = client_conns.get_mut(&client_fd).unwrap();
+    client.passthrough = true;
+    client.listener = listener;
+}
+
+// Bytes of a passthrough client's ClientHello. Once it is complete, its SNI
+// picks the pool and the client is relayed like a TCP listener's.
+fn when_passthrough_data(
+    client_fd: RawFd,
+    data: &[u8],
+    conn_db_sock_fd: i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) => client,
+        None => return,
+    };
+    client.request_buf.extend_from_slice(data);
+    let sni = match client_hello_sni(&client.request_buf) {
+        ClientHelloSni::Incomplete => return,
+        ClientHelloSni::Done(sni) => sni.map(|sni| normalize_host(&sni)),
+        ClientHelloSni::Invalid => {
+            close_client(kq, client_fd, client_conns);
+            return;
+        }
+    };
+
+    let listener = &config.listeners[client.listener];
+    let pool = sni
+        .and_then(|sni| {
+            listener
+                .sni_pools
+                .iter()
+                .find(|(hosts, _)| hosts.iter().any(|host| host.matches(&sni)))
+                .map(|(_, pool)| pool.clone())
+        })
+        .or_else(|| listener.pool.clone());
+    let pool = match pool {
+        Some(pool) => pool,
+        None => {
+            close_client(kq, client_fd, client_conns);
+            return;
+        }
+    };
+
+    // The hello goes to the backend first.
+    client.tunnel_out.append(&mut client.request_buf);
+    request_raw_backend(kq, client_fd, &pool, conn_db_sock_fd, req_map, addr, addr_len, client_conns, config);
+}
+
+// Moves every complete request in the client's buffer onto its pipeline, in
+// arrival order. On a bad request, returns the status to answer it with.
+fn extract_requests(kq: i32, client_fd: RawFd, client: &mut ClientConn, config: &Config) -> Result<(), u16> {
+    loop {
+        if client.request_framing.is_none() {
+            if client.request_buf.is_empty() {
+                return Ok(());
+            }
+
+            let (request, head_len) = match parse_request_head(&client.request_buf) {
+                Ok(Some((_, head_len))) if head_len > config.max_request_header_bytes => return Err(431),
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => {
+                    if client.request_buf.len() > config.max_request_header_bytes {
+                        return Err(431);
+                    }
+                    return Ok(());
+                }
+                Err(_) => return Err(400),
+            };
+            let framing = BodyFraming::for_request(&request).map_err(|_| 400u16)?;
+            if matches!(framing, BodyFraming::ContentLength(len) if len > config.max_request_body_bytes) {
+                return Err(413);
+            }
+
+            // We buffer the body ourselves, so tell a waiting client to send it.
+            // Not while earlier responses are pending: it would land among them.
+            let expects_continue = request
+                .headers()
+                .get(header::EXPECT)
+                .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+            if expects_continue
+                && client.request_buf.len() == head_len
+                && !matches!(framing, BodyFraming::Empty)
+                && !client.in_flight
+                && client.pipeline.size() == 0
+            {
+                client.out.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                flush_client(kq, client_fd, client, config);
+            }
+
+            client.request_head_len = head_len;
+            client.request_body_len = 0;
+            client.request_framing = Some(framing);
+        }
+
+        let body_start = client.request_head_len + client.request_body_len;
+        let framing = client.request_framing.as_mut().unwrap();
+        let (used, done) = framing.advance(&client.request_buf[body_start..]).map_err(|_| 400u16)?;
+        client.request_body_len += used;
+        if client.request_body_len as u64 > config.max_request_body_bytes {
+            return Err(413);
+        }
+        if !done {
+            return Ok(());
+        }
+
+        let request_len = client.request_head_len + client.request_body_len;
+        let mut req_data: Vec<u8> = client.request_buf.drain(..request_len).collect();
+        if matches!(client.request_framing.take(), Some(BodyFraming::Chunked(_))) {
+            let body = normalize_chunked(&req_data[client.request_head_len..]).map_err(|_| 400u16)?;
+            req_data.truncate(client.request_head_len);
+            req_data.extend_from_slice(&body);
+        }
+        let _ = client.pipeline.add(REQ { req_data, ..Default::default() });
+    }
+}
+
+// Sends the client's next pipelined request to conn_db, unless one is still
+// being answered; responses go back in request order.
+fn dispatch_next(
+    client_fd: RawFd,
+    conn_db_sock_fd: i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    kq: i32,
+    addr: sockaddr_un,
+    addr_len: u32,
+    client_counter: &mut i32,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) {
+    let client = match client_conns.get_mut(&client_fd) {
+        Some(client) if !client.in_flight => client,
+        _ => return,
+    };
+    let mut req = match client.pipeline.remove() {
+        Ok(req) => req,
+        Err(_) => {
+            if let Some(status) = client.pending_error.take() {
+                fail_client(kq, client_fd, status, client_conns, config);
+            }
+            return;
+        }
+    };
+    let mut request = match parse_http_request(&req.req_data) {
+        Ok(request) => request,
+        Err(_) => {
+            fail_client(kq, client_fd, 400, client_conns, config);
+            return;
+        }
+    };
+
+    // Routing sees the Host the client sent, before modify_headers rewrites it.
+    let host = request_host(&request);
+    if config.reject_unknown_hosts && !is_known_host(&config.routes, host.as_deref()) {
+        fail_client(kq, client_fd, 421, client_conns, config);
+        return;
+    }
+
+    // Stop reading from the client until this response is written.
+    *client_counter += 1;
+    client.requests_served += 1;
+    client.keep_alive = wants_keep_alive(&request)
+        && config.keepalive_timeout_ms > 0
+        && client.requests_served < config.keepalive_max_requests;
+    client.head_request = request.method() == Method::HEAD;
+    client.http10 = request.version() == Version::HTTP_10;
+    client.upgrade_requested = requested_upgrade(&request).is_some();
+    client.in_flight = true;
+    client.response_head.clear();
+    client.framing = None;
+    client.reusable = false;
+    client.dechunk_response = false;
+    client.chunk_response = false;
+    client.response_done = false;
+    req.request_id = new_request_id();
+    req.tls = client.tls.is_some();
+
+    client.request_id = req.request_id.clone();
+    client.client_ip = get_client_ip(client_fd).unwrap_or_default();
+    client.backend_addr.clear();
+    client.host = host.clone().unwrap_or_default();
+    del_fd_timer(kq, client_fd as usize);
+    del_fd_to_kqueue(kq, client_fd as usize);
+
+    // Routes match the normalized path, and the backend gets that one too.
+    let mut rewritten = false;
+    if let Some(target) = normalize_target(request.uri()) {
+        *request.uri_mut() = target;
+        rewritten = true;
+    }
+
+    // Pick the pool; a route may also change the path the backend sees.
+    let scheme = if client.tls.is_some() { "https" } else { "http" };
+    let route = route_request(&config.routes, &request, host.as_deref(), scheme);
+    let peer_certs = client.tls.as_ref().and_then(|tls| tls.peer_certificates());
+    let mut cert_verified = client.client_cert_verified && peer_certs.is_some();
+    if let Some(roots) = route.and_then(|(_, route)| route.client_ca.as_ref()) {
+        if !peer_certs.is_some_and(|certs| verify_client_chain(certs, roots)) {
+            fail_client(kq, client_fd, 403, client_conns, config);
+            return;
+        }
+        cert_verified = true;
+    }
+    if cert_verified && config.client_cert_header.is_some() {
+        req.client_cert = peer_certs.and_then(|certs| certs.first()).map(client_cert_summary);
+    }
+    match route {
+        Some((index, route)) => {
+            req.pool = route.pool.clone();
+            req.route = Some(index);
+            if let Some(target) = route.rewrite_target(request.uri()) {
+                *request.uri_mut() = target;
+                rewritten = true;
+            }
+        }
+        None => req.pool = client.default_pool.clone(),
+    }
+    client.pool = req.pool.clone();
+    client.route = req.route;
+    client.request_uri = request_uri(request.uri());
+
+    // Redirects and fixed responses are answered here.
+    if let Some(response) = route.and_then(|(_, route)| route_response(route, client)) {
+        client.framing = Some(BodyFraming::Empty);
+        client.response_done = true;
+        client.out.extend_from_slice(&response);
+        if !flush_client(kq, client_fd, client, config) {
+            close_client(kq, client_fd, client_conns);
+        } else if client.out.is_empty() {
+            finish_response(kq, client_fd, client_conns, config);
+        }
+        return;
+    }
+    if rewritten {
+        req.req_data = serialize_request(request).req_data;
+    }
+
+    let mut request_bytes = [0u8; 7];
+    request_bytes[1] = config.pool_id(&req.pool);
+    request_bytes[3..7].copy_from_slice(&client_fd.to_be_bytes());
+    send_to_conn_db(conn_db_sock_fd, kq, addr, addr_len, &request_bytes);
+
+    req_map.insert(client_fd, req);
+}
+
+fn when_identity_else(
+    client_fd: i32,
+    conn_db_sock_fd: i32,
+    req_map: &mut HashMap<RawFd, REQ>,
+    kq: i32,
+    data: &[u8],
+    addr: sockaddr_un,
+    addr_len: u32,
+    client_counter: &mut i32,
+    client_conns: &mut HashMap<RawFd, ClientConn>,
+    config: &Config
+) {
+    // println!("{:?}", buf);
+    // println!("a");
+    let client = client_conns.entry(client_fd).or_default();
+    if client.request_framing.is_some() {
+        // A body still arriving gets header_timeout_ms from its last progress.
+        set_fd_timer(kq, client_fd as usize, config.header_timeout_ms);
+    } else if client.request_buf.is_empty() && client.requests_served > 0 {
+        // First bytes of a request after a keep-alive wait: the head has
+        // header_timeout_ms to arrive. A new connection's timer runs from accept.
+        set_fd_timer(kq, client_fd as usize, config.header_timeout_ms);
+    }
+    client.request_buf.extend_from_slice(data);
+
+    if let Err(status) = extract_requests(kq, client_fd, client, config) {
+        if client.pipeline.size() == 0 {
+            fail_client(kq, client_fd, status, client_conns, config);
+            return;
+        }
+        // Answer the requests before it first.
+        client.pending_error = Some(status);
+    }
+
+    dispatch_next(client_fd, conn_db_sock_fd, req_map, kq, addr, addr_len, client_counter, client_conns, config);
+}
+
+#[derive(Clone, Default)]
+struct REQ {
+    req_data: Vec<u8>,
+    pool: String,
+    route: Option<usize>,
+    request_id: String,
+    // From a TCP listener: there is no request, only a byte stream.
+    raw: bool,
+    // The client connected over TLS.
+    tls: bool,
+    // For client_cert_header, when the client's certificate was verified.
+    client_cert: Option<String>,
+    // Sent again after a pooled connection failed; it must not use another.
+    fresh: bool
+}
+
+#[derive(Default)]
+struct ClientConn {
+    requests_served: u32,
+    keep_alive: bool,
+    head_request: bool,
+    http10: bool,
+    in_flight: bool,
+    // An idempotent request sent on a reused pooled connection, kept until
+    // the response starts in case the backend had already closed it.
+    retry: Option<REQ>,
+    // Bytes read from the client that are not yet a complete request.
+    request_buf: Vec<u8>,
+    request_head_len: usize,
+    request_body_len: usize,
+    request_framing: Option<BodyFraming>,
+    // Complete requests waiting for the ones before them to be answered.
+    pipeline: Queue<REQ>,
+    pending_error: Option<u16>,
+    // State of the response currently being relayed to the client.
+    response_head: Vec<u8>,
+    framing: Option<BodyFraming>,
+    reusable: bool,
+    dechunk_response: bool,
+    chunk_response: bool,
+    response_done: bool,
+    backend_fd: Option<RawFd>,
+    out: Vec<u8>,
+    // After a 101 the connection is a tunnel; client bytes wait in
+    // tunnel_out until the backend takes them.
+    upgrade_requested: bool,
+    tunnel: bool,
+    tunnel_open: bool,
+    tunnel_out: Vec<u8>,
+    // Like tls_sent, for tunnel_out to a backend in a TLS pool.
+    tunnel_tls_sent: usize,
+    // Raw TCP clients, and which side has sent or been sent EOF.
+    raw: bool,
+    // From a passthrough listener, config.listeners[listener]; raw once the
+    // pool is picked.
+    passthrough: bool,
+    listener: usize,
+    client_eof: bool,
+    backend_eof: bool,
+    client_shut: bool,
+    backend_shut: bool,
+    // Pool for requests no route matches; set by the listener.
+    default_pool: String,
+    // Used by response header rules.
+    pool: String,
+    route: Option<usize>,
+    request_id: String,
+    client_ip: String,
+    backend_addr: String,
+    host: String,
+    request_uri: String,
+    // Set on TLS listeners. tls_sent counts the bytes at the front of out
+    // that were handed to it but may not have reached the socket yet.
+    tls: Option<Box<ServerConnection>>,
+    tls_sent: usize,
+    // The listener checked any client certificate against its client_ca
+    // during the handshake.
+    client_cert_verified: bool
+}
+
+// `listener_fds` holds the socket of each of config.listeners, in order, or
+// -1 for a listener this worker does not serve.
+pub fn worker_loop(sock_fd: i32, listener_fds: &[RawFd], config: &Config) {
+    let listeners: HashMap<RawFd, (usize, &ListenerConfig)> = listener_fds.iter().copied().zip(config.listeners.iter().enumerate()).collect();
     let mut req_maps: HashMap<RawFd, REQ> = HashMap::new();
     let mut server_client_mapping: HashMap<RawFd, RawFd> = HashMap::new();
     let mut fd_ip_mapping: HashMap<RawFd, [u8; 6]> = HashMap::new();
     let mut server_req_mapping: HashMap<[u8; 6], HashSet<RawFd>> = HashMap::new();
-    let mut server_backend_fd_mapping: HashMap<[u8; 6], Vec<RawFd>> = HashMap::new();
+    let mut backend_pool = BackendPool::default();
+    let mut connect_attempts: HashMap<RawFd, u32> = HashMap::new();
+    let mut client_conns: HashMap<RawFd, ClientConn> = HashMap::new();
+    let mut udp_flows = UdpFlows::default();
 
     let mut client_counter = 0;
     let mut server_counter = 0;
@@ -486,6 +2298,9 @@ pub fn worker_loop(sock_fd: i32) {
 
         let kq = kqueue();
         add_fd_to_kqueue(kq, sock_fd as usize);
+        for listener_fd in listener_fds.iter().filter(|fd| **fd >= 0) {
+            add_fd_to_kqueue(kq, *listener_fd as usize);
+        }
 
         loop {
             let mut events: [kevent; 32] = zeroed();
@@ -499,10 +2314,43 @@ pub fn worker_loop(sock_fd: i32) {
                 let ev = events[i as usize];
                 if ev.filter == EVFILT_READ {
                     if ev.ident == sock_fd as usize {
-                        let client_fd = accept(sock_fd, ptr::null_mut(), ptr::null_mut());
-                        if client_fd >= 0 {
-                            add_fd_to_kqueue(kq, client_fd as usize);
+                        accept_client(kq, sock_fd, DEFAULT_POOL, None, &mut client_conns, config);
+                    } else if let Some(&(index, listener)) = listeners.get(&(ev.ident as RawFd)) {
+                        let pool = listener.pool.as_deref().unwrap_or(DEFAULT_POOL);
+                        match listener.mode {
+                            ListenerMode::Http => {
+                                let accepted = accept_client(kq, ev.ident as RawFd, pool, listener.tls.as_ref(), &mut client_conns, config);
+                                if let Some(client) = accepted.and_then(|client_fd| client_conns.get_mut(&client_fd)) {
+                                    client.client_cert_verified = listener.client_ca.is_some();
+                                }
+                            }
+                            ListenerMode::Tcp => accept_raw_client(
+                                kq,
+                                ev.ident as RawFd,
+                                pool,
+                                conn_db_sock_fd,
+                                &mut req_maps,
+                                addr,
+                                addr_len,
+                                &mut client_conns,
+                                config
+                            ),
+                            ListenerMode::Udp => when_udp_datagram(
+                                ev.ident as RawFd,
+                                pool,
+                                conn_db_sock_fd,
+                                kq,
+                                addr,
+                                addr_len,
+                                &mut udp_flows,
+                                config
+                            ),
+                            ListenerMode::Passthrough => {
+                                accept_passthrough_client(kq, ev.ident as RawFd, index, &mut client_conns, config);
+                            }
                         }
+                    } else if udp_flows.contains(ev.ident as RawFd) {
+                        when_udp_reply(ev.ident as RawFd, conn_db_sock_fd, kq, addr, addr_len, &mut udp_flows, config);
                     } else if ev.ident == conn_db_sock_fd as usize {
                         when_identity_equals_conn_db_sock_fd(
                             conn_db_sock_fd,
@@ -513,38 +2361,194 @@ pub fn worker_loop(sock_fd: i32) {
                             addr_len,
                             &mut fd_ip_mapping,
                             &mut server_req_mapping,
-                            &mut conn_db_res_counter
+                            &mut conn_db_res_counter,
+                            &mut connect_attempts,
+                            &mut backend_pool,
+                            &mut client_conns,
+                            &mut udp_flows,
+                            config
+                        );
+                    } else if backend_pool.contains(ev.ident as RawFd) {
+                        // An idle pooled connection only becomes readable when the
+                        // server closes it (or sends something we did not ask for).
+                        backend_pool.close_idle(kq, ev.ident as RawFd);
+                    } else if server_client_mapping.contains_key(&(ev.ident as RawFd)) {
+                        when_identity_backend(
+                            ev.ident as RawFd,
+                            conn_db_sock_fd,
+                            &mut server_client_mapping,
+                            kq,
+                            addr,
+                            addr_len,
+                            &mut fd_ip_mapping,
+                            &mut server_req_mapping,
+                            &mut server_counter,
+                            &mut req_maps,
+                            &mut client_conns,
+                            &mut backend_pool,
+                            config
                         );
                     } else {
                         let client_fd = ev.ident as i32;
 
                         if client_fd >= 0 {
-                            let mut buf = [0u8; 1024];
-                            let n = read(client_fd, buf.as_mut_ptr() as *mut _, 1024);
+                            let mut buf: Vec<u8> = Vec::new();
+                            let read = read_client(kq, client_fd, &mut client_conns, &mut buf, config);
                             // println!("message from: {} by {}", client_fd, std::process::id());
                             // println!("{:?} {}", buf, n);
-                            if n > 0 {
+                            if matches!(read, ClientRead::Data) && client_conns.get(&client_fd).is_some_and(|client| client.tunnel_open) {
+                                when_tunnel_client_data(
+                                    client_fd,
+                                    &buf,
+                                    conn_db_sock_fd,
+                                    &mut server_client_mapping,
+                                    kq,
+                                    addr,
+                                    addr_len,
+                                    &mut fd_ip_mapping,
+                                    &mut server_req_mapping,
+                                    &mut client_conns,
+                                    &mut backend_pool,
+                                    config
+                                );
+                            } else if matches!(read, ClientRead::Data) && client_conns.get(&client_fd).is_some_and(|client| client.passthrough) {
+                                when_passthrough_data(
+                                    client_fd,
+                                    &buf,
+                                    conn_db_sock_fd,
+                                    &mut req_maps,
+                                    kq,
+                                    addr,
+                                    addr_len,
+                                    &mut client_conns,
+                                    config
+                                );
+                            } else if matches!(read, ClientRead::Data) {
                                 when_identity_else(
                                     client_fd,
                                     conn_db_sock_fd,
                                     &mut req_maps,
+                                    kq,
+                                    &buf,
+                                    addr,
+                                    addr_len,
+                                    &mut client_counter,
+                                    &mut client_conns,
+                                    config
+                                );
+                            } else if matches!(read, ClientRead::WouldBlock) {
+                                continue;
+                            } else if matches!(read, ClientRead::Eof) && client_conns.get(&client_fd).is_some_and(|client| client.raw) {
+                                when_raw_client_eof(
+                                    client_fd,
+                                    conn_db_sock_fd,
                                     &mut server_client_mapping,
                                     kq,
-                                    buf,
-                                    n as usize,
                                     addr,
                                     addr_len,
                                     &mut fd_ip_mapping,
                                     &mut server_req_mapping,
-                                    &mut server_counter,
-                                    &mut client_counter
+                                    &mut client_conns,
+                                    &mut backend_pool,
+                                    config
                                 );
                             } else {
                                 // println!("{}, {}, {}", server_counter, client_counter, conn_db_res_counter);
                                 // println!("{}", req_maps.len());
-                                del_fd_to_kqueue(kq, client_fd as usize);
-                                close(client_fd);
+                                req_maps.remove(&client_fd);
+                                connect_attempts.remove(&client_fd);
+                                let backend_fds: Vec<RawFd> = server_client_mapping
+                                    .iter()
+                                    .filter(|(_, target_fd)| **target_fd == client_fd)
+                                    .map(|(backend_fd, _)| *backend_fd)
+                                    .collect();
+                                for backend_fd in backend_fds {
+                                    release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, &mut server_client_mapping, &mut fd_ip_mapping, &mut server_req_mapping, &mut backend_pool, config);
+                                }
+                                close_client(kq, client_fd, &mut client_conns);
+                            }
+                        }
+                    }
+                } else if ev.filter == EVFILT_USER {
+                    dispatch_next(
+                        ev.ident as RawFd,
+                        conn_db_sock_fd,
+                        &mut req_maps,
+                        kq,
+                        addr,
+                        addr_len,
+                        &mut client_counter,
+                        &mut client_conns,
+                        config
+                    );
+                } else if ev.filter == EVFILT_WRITE && server_client_mapping.contains_key(&(ev.ident as RawFd)) {
+                    when_backend_writable(
+                        ev.ident as RawFd,
+                        conn_db_sock_fd,
+                        &mut server_client_mapping,
+                        kq,
+                        addr,
+                        addr_len,
+                        &mut fd_ip_mapping,
+                        &mut server_req_mapping,
+                        &mut client_conns,
+                        &mut backend_pool,
+                        config
+                    );
+                } else if ev.filter == EVFILT_WRITE {
+                    when_client_writable(
+                        ev.ident as RawFd,
+                        conn_db_sock_fd,
+                        &mut server_client_mapping,
+                        kq,
+                        addr,
+                        addr_len,
+                        &mut fd_ip_mapping,
+                        &mut server_req_mapping,
+                        &mut client_conns,
+                        &mut backend_pool,
+                        config
+                    );
+                } else if ev.filter == EVFILT_TIMER {
+                    let timer_fd = ev.ident as RawFd;
+                    if let Some(&target_fd) = server_client_mapping.get(&timer_fd) {
+                        // The backend went quiet for backend_timeout_ms, or a
+                        // tunnel saw no traffic for tunnel_idle_timeout_ms (then
+                        // fail_client just closes).
+                        let handshaking = backend_pool.handshaking(timer_fd);
+                        if let Some(server) = fd_ip_mapping.get(&timer_fd).filter(|_| handshaking) {
+                            eprintln!("TLS handshake with {} timed out", server_addr(server));
+                        }
+                        release_backend(timer_fd, None, conn_db_sock_fd, kq, addr, addr_len, &mut server_client_mapping, &mut fd_ip_mapping, &mut server_req_mapping, &mut backend_pool, config);
+                        fail_client(kq, target_fd, 504, &mut client_conns, config);
+                    } else if backend_pool.contains(timer_fd) {
+                        // Pooled connection sat idle for pool_idle_timeout_ms.
+                        backend_pool.close_idle(kq, timer_fd);
+                    } else if udp_flows.contains(timer_fd) {
+                        // No datagrams either way for udp_flow_timeout_ms.
+                        udp_flows.expire(timer_fd, conn_db_sock_fd, kq, addr, addr_len);
+                    } else if let Some(client) = client_conns.get(&timer_fd) {
+                        if !client.out.is_empty() || tls_pending(client) {
+                            // The client took none of its output for
+                            // backend_timeout_ms (tunnel_idle_timeout_ms for a
+                            // tunnel), so neither side's connection is kept.
+                            if let Some(backend_fd) = client.backend_fd {
+                                release_backend(backend_fd, None, conn_db_sock_fd, kq, addr, addr_len, &mut server_client_mapping, &mut fd_ip_mapping, &mut server_req_mapping, &mut backend_pool, config);
                             }
+                            close_client(kq, timer_fd, &mut client_conns);
+                            continue;
+                        }
+                        if client.in_flight {
+                            continue;
+                        }
+                        if client.request_buf.is_empty() {
+                            // A keep-alive client stayed idle for keepalive_timeout_ms,
+                            // or a new one sent nothing within header_timeout_ms.
+                            close_client(kq, timer_fd, &mut client_conns);
+                        } else {
+                            // The request head did not arrive within header_timeout_ms,
+                            // or its body stopped arriving for that long.
+                            fail_client(kq, timer_fd, 408, &mut client_conns, config);
                         }
                     }
                 }
@@ -554,3 +2558,49 @@ pub fn worker_loop(sock_fd: i32) {
         del_fd_to_kqueue(kq, sock_fd as usize);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_pair() -> (RawFd, RawFd) {
+        let mut fds = [0; 2];
+        unsafe {
+            assert_eq!(socketpair(AF_UNIX, SOCK_STREAM, 0, fds.as_mut_ptr()), 0);
+        }
+        (fds[0], fds[1])
+    }
+
+    #[test]
+    fn idle_connections_are_kept_per_pool() {
+        let kq = unsafe { kqueue() };
+        let server = [127, 0, 0, 1, 0x0b, 0xb8];
+        let (fd, peer) = socket_pair();
+        let mut backend_pool = BackendPool::default();
+        backend_pool.put((0, server), fd);
+        assert_eq!(backend_pool.idle_count(&(0, server)), 1);
+        assert_eq!(backend_pool.idle_count(&(1, server)), 0);
+
+        // Another pool sending to the same backend does not get it.
+        assert_eq!(backend_pool.take(kq, &(1, server)), None);
+        assert!(backend_pool.contains(fd));
+        assert_eq!(backend_pool.take(kq, &(0, server)), Some(fd));
+        assert!(!backend_pool.contains(fd));
+        unsafe {
+            close(fd);
+            close(peer);
+        }
+
+        // One the server closed while it was idle is not handed out.
+        let (fd, peer) = socket_pair();
+        backend_pool.put((1, server), fd);
+        unsafe {
+            close(peer);
+        }
+        assert_eq!(backend_pool.take(kq, &(1, server)), None);
+        assert_eq!(backend_pool.idle_count(&(1, server)), 0);
+        unsafe {
+            close(kq);
+        }
+    }
+}